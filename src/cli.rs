@@ -1 +1,3 @@
-pub use super::cli_types::{Cli, Commands};
+pub use super::cli_types::{
+    Cli, Commands, FilterCommands, GitCommands, KeyCommands, SecretCommands,
+};
@@ -63,6 +63,15 @@ pub enum Commands {
     Encrypt {
         #[arg(name = "PACK_PATH")]
         paths: Vec<PathBuf>,
+        /// Only check, exit non-zero if any plaintext block is left (e.g. as a pre-commit guard)
+        #[arg(long = "check", conflicts_with = "dry_run")]
+        check: bool,
+        /// List how many blocks each file would have sealed, without writing anything
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// Only process these pack files; the packs are found from the files if no pack is given
+        #[arg(long = "file", value_name = "FILE")]
+        files: Vec<PathBuf>,
     },
     /// Scan files in the given pack for replacement variables, decrypt them,
     /// and replace them back to the original files
@@ -70,6 +79,49 @@ pub enum Commands {
     Decrypt {
         #[arg(name = "PACK_PATH")]
         paths: Vec<PathBuf>,
+        /// List how many blocks each file would have opened, without writing anything
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// Only process these pack files; the packs are found from the files if no pack is given
+        #[arg(long = "file", value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+    /// Try to decrypt every encrypted block of the given packs without writing anything,
+    /// report the blocks that fail by file and line
+    #[command(arg_required_else_help = true)]
+    Verify {
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+    },
+    /// Scan packs for plaintext secrets outside encryption markers, report them by file and line
+    ///
+    /// Looks for known token shapes (AWS keys, GitHub and Slack tokens, private key headers),
+    /// `password=` style assignments and high-entropy strings
+    Audit {
+        /// Optional pack paths; if omitted, audit all installed packs
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+        /// Wrap the findings in the pack's plaintext markers, then run `stow-cm encrypt` to seal them
+        #[arg(long = "fix")]
+        fix: bool,
+    },
+    /// Decrypt a pack file to a private temp file, open $EDITOR and encrypt it again on save
+    #[command(arg_required_else_help = true)]
+    Edit {
+        #[arg(name = "PACK_FILE")]
+        path: PathBuf,
+    },
+    /// Print the decrypted content of a pack file
+    #[command(arg_required_else_help = true)]
+    Cat {
+        #[arg(name = "PACK_FILE")]
+        path: PathBuf,
+    },
+    /// Manage encryption keys
+    #[command(arg_required_else_help = true)]
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
     },
     /// List all installed packs and their status
     List {
@@ -86,6 +138,10 @@ pub enum Commands {
         /// Stow repository directory (packs will be created here)
         #[arg(short = 't', long = "to", value_name = "STOW_DIR")]
         to: PathBuf,
+        /// Enable encryption in the generated config, review detected secrets,
+        /// then wrap and encrypt them before installing
+        #[arg(long = "encrypt")]
+        encrypt: bool,
     },
     /// Check consistency between installed links and the filesystem
     Status {
@@ -99,6 +155,48 @@ pub enum Commands {
         #[arg(long = "json")]
         json: bool,
     },
+    /// Show differences between installed copies / decrypted files and the pack sources
+    Diff {
+        /// Optional pack paths; if omitted, diff all installed packs
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+    },
+    /// Watch installed packs and keep links and decrypted files in sync
+    Watch {
+        /// Optional pack paths; if omitted, watch all installed packs
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+    },
+    /// Re-seal unbound encrypted blocks so they are bound to their file path (needs `bind_blocks`)
+    Reseal {
+        /// Optional pack paths; if omitted, reseal all installed packs
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+    },
+    /// Re-create missing decrypted files and their links, e.g. after a reboot cleared `runtime_dir`
+    RelinkSecrets {
+        /// Optional pack paths; if omitted, relink all installed packs
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+    },
+    /// Manage the encrypted secret vault referenced by `&{secret:NAME}` blocks
+    #[command(arg_required_else_help = true)]
+    Secret {
+        #[command(subcommand)]
+        command: SecretCommands,
+    },
+    /// Git filter for encrypted packs: read a file on stdin and write it to stdout
+    #[command(arg_required_else_help = true)]
+    Filter {
+        #[command(subcommand)]
+        command: FilterCommands,
+    },
+    /// Git integration
+    #[command(arg_required_else_help = true)]
+    Git {
+        #[command(subcommand)]
+        command: GitCommands,
+    },
     /// Initialize a new pack directory with stow-cm.toml
     #[command(arg_required_else_help = true)]
     Init {
@@ -110,3 +208,123 @@ pub enum Commands {
         use_defaults: bool,
     },
 }
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommands {
+    /// Generate a random base64 key for the given algorithm (file mode 0600)
+    #[command(arg_required_else_help = true)]
+    Gen {
+        /// Path of the key file to write
+        #[arg(name = "KEY_PATH")]
+        path: PathBuf,
+        /// Encryption algorithm: ChaCha20-Poly1305 | AES-128-GCM | AES-256-GCM | X25519
+        #[arg(long = "alg", default_value = "ChaCha20-Poly1305")]
+        alg: String,
+        /// Overwrite the key file if it already exists
+        #[arg(long = "force")]
+        force: bool,
+    },
+    /// Re-encrypt every block in the given packs (or all installed packs using --old-key) with a new key
+    Rotate {
+        /// Optional pack paths; if omitted, rotate all installed packs that use --old-key
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+        /// Path of the new key file
+        #[arg(long = "new-key", value_name = "KEY_PATH", required = true)]
+        new_key: PathBuf,
+        /// Path of the old key file (default: each pack's configured key)
+        #[arg(long = "old-key", value_name = "KEY_PATH")]
+        old_key: Option<PathBuf>,
+    },
+    /// Print the base64 public key (recipient) of an X25519 identity file
+    #[command(arg_required_else_help = true)]
+    Pub {
+        /// Path of the X25519 identity file
+        #[arg(name = "KEY_PATH")]
+        path: PathBuf,
+    },
+    /// Re-wrap X25519 blocks to the currently configured recipients (ciphertext is unchanged)
+    Rewrap {
+        /// Optional pack paths; if omitted, rewrap all installed X25519 packs
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SecretCommands {
+    /// Set a secret, prompted on a terminal or read from stdin
+    #[command(arg_required_else_help = true)]
+    Set {
+        #[arg(name = "NAME")]
+        name: String,
+        /// Pack whose vault to use
+        #[arg(long = "pack", value_name = "PACK_PATH", default_value = ".")]
+        pack: PathBuf,
+    },
+    /// Print a secret
+    #[command(arg_required_else_help = true)]
+    Get {
+        #[arg(name = "NAME")]
+        name: String,
+        /// Pack whose vault to use
+        #[arg(long = "pack", value_name = "PACK_PATH", default_value = ".")]
+        pack: PathBuf,
+    },
+    /// List secret names
+    List {
+        /// Pack whose vault to use
+        #[arg(long = "pack", value_name = "PACK_PATH", default_value = ".")]
+        pack: PathBuf,
+    },
+    /// Remove a secret
+    #[command(arg_required_else_help = true)]
+    Rm {
+        #[arg(name = "NAME")]
+        name: String,
+        /// Pack whose vault to use
+        #[arg(long = "pack", value_name = "PACK_PATH", default_value = ".")]
+        pack: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FilterCommands {
+    /// Encrypt plaintext blocks, unchanged blocks keep their ciphertext (git clean filter)
+    #[command(arg_required_else_help = true)]
+    Clean {
+        /// Path of the file relative to the repository root, as passed by git (`%f`)
+        #[arg(name = "PATH")]
+        path: PathBuf,
+    },
+    /// Decrypt ciphertext blocks back to plaintext blocks (git smudge filter)
+    #[command(arg_required_else_help = true)]
+    Smudge {
+        /// Path of the file relative to the repository root, as passed by git (`%f`)
+        #[arg(name = "PATH")]
+        path: PathBuf,
+    },
+    /// Print a file with its ciphertext blocks decrypted (git diff textconv)
+    #[command(arg_required_else_help = true)]
+    Textconv {
+        /// Pack the file belongs to
+        #[arg(long = "pack", value_name = "PACK_PATH", required = true)]
+        pack: PathBuf,
+        /// Path of the file relative to the pack, set by `git setup` for each file with ciphertext
+        #[arg(long = "path", value_name = "REL_PATH")]
+        path: Option<PathBuf>,
+        /// File to convert, as passed by git
+        #[arg(name = "FILE")]
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GitCommands {
+    /// Write `.gitattributes` and git config entries so commits only contain ciphertext
+    #[command(arg_required_else_help = true)]
+    Setup {
+        #[arg(name = "PACK_PATH")]
+        paths: Vec<PathBuf>,
+    },
+}
@@ -1,17 +1,21 @@
 use std::ffi::OsStr;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::{anyhow, bail};
+use anyhow::{Context, anyhow, bail};
 use log::{info, warn};
+use serde::Serialize;
 
-use crate::config::Config;
+use crate::config::{self, Config, EncryptedConfig};
 use crate::constants::CONFIG_FILE_NAME;
 use crate::error::Result;
 use crate::merge_tree::{MergeOption, MergeTree};
+use crate::secret_scan::{self, Finding};
 use crate::symlink::{Symlink, SymlinkMode};
 use crate::util;
 
+use super::crypto::{CryptoOptions, encrypt, text_files};
 use super::install;
 use super::resolve_track_file;
 
@@ -19,7 +23,15 @@ use super::resolve_track_file;
 ///
 /// 每个 source 目录以其 basename 作为 pack 名，在 `stow_dir` 下创建 pack 目录，
 /// 自动生成 `stow-cm.toml`（target = source），然后移入文件并安装链接。
-pub fn adopt(global: &Config, sources: &[PathBuf], stow_dir: impl AsRef<Path>) -> Result<()> {
+///
+/// `encrypt` 时生成的配置启用 `[encrypted]`，移入前扫描疑似 secret 并逐个确认，
+/// 移入后以明文块标记包裹并加密，再安装链接
+pub fn adopt(
+    global: &Config,
+    sources: &[PathBuf],
+    stow_dir: impl AsRef<Path>,
+    encrypt: bool,
+) -> Result<()> {
     let stow_dir = stow_dir.as_ref();
 
     for source in sources {
@@ -30,14 +42,20 @@ pub fn adopt(global: &Config, sources: &[PathBuf], stow_dir: impl AsRef<Path>) -
 
         let pack_dir = stow_dir.join(pack_name);
         util::scoped_log_prefix(pack_name, || {
-            adopt_one(global, source, &pack_dir, pack_name)
+            adopt_one(global, source, &pack_dir, pack_name, encrypt)
         })?;
     }
 
     Ok(())
 }
 
-fn adopt_one(global: &Config, source: &Path, pack_dir: &Path, pack_name: &str) -> Result<()> {
+fn adopt_one(
+    global: &Config,
+    source: &Path,
+    pack_dir: &Path,
+    pack_name: &str,
+    encrypt_secrets: bool,
+) -> Result<()> {
     info!("source={} pack={}", source.display(), pack_dir.display());
 
     let config_path = pack_dir.join(CONFIG_FILE_NAME);
@@ -56,14 +74,60 @@ fn adopt_one(global: &Config, source: &Path, pack_dir: &Path, pack_name: &str) -
     }
 
     // 确保 pack 目录存在
+    let created_dir = !pack_dir.exists();
     std::fs::create_dir_all(pack_dir)?;
 
     // 如果没有 stow-cm.toml，先生成配置（含 target），再加载
-    if !config_path.exists() {
-        generate_config(&config_path, pack_name, source)?;
-        info!("generated stow-cm.toml");
+    let generated = !config_path.exists();
+    let prepared = if generated {
+        let encrypted = encrypt_secrets
+            .then_some(global.encrypted.as_ref())
+            .flatten();
+        generate_config(&config_path, pack_name, source, encrypt_secrets, encrypted)
+            .inspect(|()| info!("generated stow-cm.toml"))
+    } else {
+        Ok(())
     }
+    .and_then(|()| prepare(global, source, pack_dir, pack_name, encrypt_secrets));
+
+    // 移入文件前失败时删除本次生成的配置与目录，避免下次 adopt 将其当作用户提供的配置
+    let (config, to_create, detected) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            if generated {
+                let _ = std::fs::remove_file(&config_path);
+            }
+            if created_dir {
+                let _ = std::fs::remove_dir(pack_dir);
+            }
+            return Err(e);
+        }
+    };
+
+    // 将 merge_tree 返回的源文件移入 pack
+    adopt_move_files(&to_create)?;
+
+    let config = Arc::new(config);
+    if encrypt_secrets {
+        wrap_secrets(&config, pack_dir, pack_name, &detected)?;
+        encrypt(&config, pack_dir, &CryptoOptions::default())?;
+    }
+
+    // 复用 install 创建链接 + 写 track file + 执行 init 脚本
+    install::install(&config, pack_dir)?;
 
+    Ok(())
+}
+
+/// 移入文件前的检查：target 与 source 一致、未安装、无冲突、加密可用，并确认待包裹的 secret。
+/// 返回 pack 配置、待移入的文件与确认后的 secret
+fn prepare(
+    global: &Config,
+    source: &Path,
+    pack_dir: &Path,
+    pack_name: &str,
+    encrypt_secrets: bool,
+) -> Result<(Config, Vec<Symlink>, Vec<Detected>)> {
     let config = Config::for_pack(pack_dir, global, None, false)?;
     let target = config
         .target
@@ -88,6 +152,18 @@ fn adopt_one(global: &Config, source: &Path, pack_dir: &Path, pack_name: &str) -
         bail!("{pack_name}: pack has been installed, cannot adopt");
     }
 
+    // 移入文件前确认加密可用，避免 secret 以明文进入仓库
+    if encrypt_secrets {
+        let encrypted = config
+            .encrypted
+            .as_ref()
+            .filter(|it| it.enable.is_some_and(std::convert::identity))
+            .ok_or_else(|| {
+                anyhow!("{pack_name}: encryption is not enabled in {CONFIG_FILE_NAME}")
+            })?;
+        encrypted.resolve(pack_name)?;
+    }
+
     let ignore_re = config.ignore_regex()?;
 
     // 用 merge_tree 扫描 source 目录，检测与 pack 的冲突，同时获取需移动的文件列表
@@ -111,29 +187,80 @@ fn adopt_one(global: &Config, source: &Path, pack_dir: &Path, pack_name: &str) -
         );
     }
 
-    // 将 merge_tree 返回的源文件移入 pack
-    if let Some(ref to_create) = merge_result.to_create_symlinks {
-        adopt_move_files(to_create)?;
-    }
-
-    // 复用 install 创建链接 + 写 track file + 执行 init 脚本
-    let config = Arc::new(config);
-    install::install(&config, pack_dir)?;
-
-    Ok(())
+    let to_create = merge_result.to_create_symlinks.unwrap_or_default();
+    let detected = if encrypt_secrets {
+        review(detect_secrets(&config, pack_dir, pack_name, &to_create)?)?
+    } else {
+        Vec::new()
+    };
+    Ok((config, to_create, detected))
 }
 
 /// 为新 pack 生成最小 `stow-cm.toml`。
 /// `target` 不显式写出，由全局配置默认值继承；
 /// 注释记录源目录路径，供 `for_pack` 比对校验。
-fn generate_config(config_path: &Path, pack_name: &str, source: &Path) -> Result<()> {
-    let content = format!(
+/// `encrypt` 时写出启用的 `[encrypted]`，只含与机器无关的算法、边界与 `decrypted_path` 模板；
+/// 密钥来源（`key_path`、`key_cmd`、`vault` 等）不写入会提交到仓库的配置，逐字段继承全局配置。
+fn generate_config(
+    config_path: &Path,
+    pack_name: &str,
+    source: &Path,
+    encrypt: bool,
+    encrypted: Option<&EncryptedConfig>,
+) -> Result<()> {
+    let mut content = format!(
         "# Auto-generated by stow-cm adopt\n\
          name = \"{pack_name}\"\n\
          # target inherits from global config (default: ${{XDG_CONFIG_HOME:-~/.config}}/${{PACK_NAME}}/);\n\
          # for this pack it resolves to: \"{}\"\n",
         source.display()
     );
+    if encrypt {
+        #[derive(Serialize)]
+        struct Section {
+            encrypted: EncryptedConfig,
+        }
+        // 全局配置只写了部分字段时，补全安装所需的算法、边界与解密路径
+        let defaults = EncryptedConfig::default();
+        let global = encrypted.cloned().unwrap_or_else(|| defaults.clone());
+        let section = Section {
+            encrypted: EncryptedConfig {
+                enable: Some(true),
+                // 写出模板而非本机展开后的默认路径，pack 可在其他机器上使用
+                decrypted_path: global
+                    .decrypted_path
+                    .filter(|it| Some(it) != defaults.decrypted_path.as_ref())
+                    .or_else(|| {
+                        Some(
+                            "${XDG_STATE_HOME:-~/.local/state}/stow-cm/${PACK_ID}/decrypted/"
+                                .into(),
+                        )
+                    }),
+                runtime_dir: None,
+                encrypted_alg: global.encrypted_alg.or(defaults.encrypted_alg),
+                left_boundary: global.left_boundary.or(defaults.left_boundary),
+                right_boundary: global.right_boundary.or(defaults.right_boundary),
+                plain_left_boundary: global.plain_left_boundary.or(defaults.plain_left_boundary),
+                plain_right_boundary: global
+                    .plain_right_boundary
+                    .or(defaults.plain_right_boundary),
+                key_path: None,
+                key_cmd: None,
+                key_env: None,
+                passphrase: None,
+                keyring: None,
+                recipients: None,
+                files: None,
+                bind_blocks: None,
+                vault: None,
+                rules: None,
+                on_missing_key: None,
+                placeholder: None,
+            },
+        };
+        content.push('\n');
+        content.push_str(&toml::to_string(&section)?);
+    }
 
     std::fs::write(config_path, content).map_err(|e| {
         anyhow!(
@@ -144,6 +271,141 @@ fn generate_config(config_path: &Path, pack_name: &str, source: &Path) -> Result
     Ok(())
 }
 
+/// 移入 pack 后待包裹 secret 的文件
+struct Detected {
+    /// 移入后在 pack 中的路径
+    path: PathBuf,
+    content: String,
+    findings: Vec<Finding>,
+}
+
+/// 扫描待移入的文本文件中的疑似 secret；整文件加密的文件跳过，
+/// 无法以标记包裹的发现（如私钥）只给出提示
+fn detect_secrets(
+    config: &Config,
+    pack_dir: &Path,
+    pack_name: &str,
+    to_move: &[Symlink],
+) -> Result<Vec<Detected>> {
+    let Some(encrypted) = config.encrypted.as_ref() else {
+        return Ok(Vec::new());
+    };
+    let marker_set = encrypted.markers(pack_name)?;
+    let files_re = encrypted.files_regex(pack_name)?;
+    let ignore_re = config.ignore_regex()?;
+
+    let mut detected = Vec::new();
+    for symlink in to_move {
+        for src in text_files(&symlink.src, ignore_re.as_ref()) {
+            let path = match src.strip_prefix(&symlink.src) {
+                Ok(rel) if !rel.as_os_str().is_empty() => symlink.dst.join(rel),
+                _ => symlink.dst.clone(),
+            };
+            let rel_path = path.strip_prefix(pack_dir).unwrap_or(&path);
+            if config::is_whole_file(rel_path, files_re.as_ref()) {
+                continue;
+            }
+            let Ok(content) = std::fs::read_to_string(&src) else {
+                continue;
+            };
+            let markers = marker_set.get(rel_path);
+            let (findings, rest): (Vec<_>, Vec<_>) = secret_scan::scan(&content, markers)
+                .into_iter()
+                .partition(|it| it.wrappable(&content, markers));
+            for finding in rest {
+                warn!(
+                    "{}:{}: {} cannot be wrapped, add the file to encrypted.files to encrypt it as a whole",
+                    path.display(),
+                    finding.line(&content),
+                    finding.kind
+                );
+            }
+            if !findings.is_empty() {
+                detected.push(Detected {
+                    path,
+                    content,
+                    findings,
+                });
+            }
+        }
+    }
+    Ok(detected)
+}
+
+/// 逐个列出检测到的值供确认，默认全选；非终端时全部保留
+fn review(mut detected: Vec<Detected>) -> Result<Vec<Detected>> {
+    let labels: Vec<String> = detected
+        .iter()
+        .flat_map(|file| {
+            file.findings.iter().map(|finding| {
+                format!(
+                    "{}:{}: {}: {}",
+                    file.path.display(),
+                    finding.line(&file.content),
+                    finding.kind,
+                    file.content.get(finding.range.clone()).unwrap_or_default()
+                )
+            })
+        })
+        .collect();
+    if labels.is_empty() {
+        info!("no secret detected");
+        return Ok(detected);
+    }
+    if !std::io::stdin().is_terminal() {
+        for label in &labels {
+            info!("detected {label}");
+        }
+        return Ok(detected);
+    }
+
+    let selected: Vec<usize> = inquire::MultiSelect::new("secrets to encrypt:", labels)
+        .with_all_selected_by_default()
+        .raw_prompt()
+        .context("adopt aborted")?
+        .into_iter()
+        .map(|it| it.index)
+        .collect();
+    let mut index = 0;
+    for file in &mut detected {
+        file.findings.retain(|_| {
+            let keep = selected.contains(&index);
+            index += 1;
+            keep
+        });
+    }
+    detected.retain(|it| !it.findings.is_empty());
+    Ok(detected)
+}
+
+/// 以明文块标记包裹确认过的 secret，之后由 encrypt 加密
+fn wrap_secrets(
+    config: &Config,
+    pack_dir: &Path,
+    pack_name: &str,
+    detected: &[Detected],
+) -> Result<()> {
+    let Some(encrypted) = config.encrypted.as_ref() else {
+        return Ok(());
+    };
+    let marker_set = encrypted.markers(pack_name)?;
+    for file in detected {
+        let markers = marker_set.get(file.path.strip_prefix(pack_dir).unwrap_or(&file.path));
+        let temp = util::stage_write(
+            &file.path,
+            secret_scan::wrap(&file.content, &file.findings, markers),
+        )?;
+        std::fs::rename(&temp, &file.path)
+            .with_context(|| format!("failed to write {}", file.path.display()))?;
+        info!(
+            "wrap {} secret(s) in {}",
+            file.findings.len(),
+            file.path.display()
+        );
+    }
+    Ok(())
+}
+
 /// 将 `merge_tree` 扫描结果中的文件从 source 移入 pack。
 ///
 /// 每个 Symlink 的 `src` 是 source 目录中的路径，`dst` 是 pack 目录中的对应位置。
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+use anyhow::bail;
+use log::{info, warn};
+
+use crate::config::{self, Config};
+use crate::crypto;
+use crate::error::Result;
+use crate::secret_scan;
+use crate::util;
+
+use super::crypto::{is_text, pack_files};
+use super::installed_pack_paths;
+
+/// 扫描 pack 文本文件中加密标记之外的明文 secret（已知 token 格式、`password=` 赋值、高熵字符串），
+/// 逐个输出 `path:line: kind`，不输出 secret 本身；匹配 `files` 但尚未加密的文件整体视为一个发现。
+///
+/// `fix` 时以明文块标记包裹可包裹的发现，之后由 `stow-cm encrypt`（或 git clean filter）加密。
+/// 不传 `paths` 则扫描所有已安装 pack；存在未处理的发现时返回错误
+pub fn audit(global: &Config, paths: Vec<PathBuf>, fix: bool) -> Result<()> {
+    let packs = if paths.is_empty() {
+        installed_pack_paths()?
+    } else {
+        util::canonicalize(paths)?
+    };
+
+    let mut left_count = 0_usize;
+    for pack in &packs {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        util::scoped_log_prefix(&pack_name, || -> Result<()> {
+            let Some(encrypted) = config.encrypted.as_ref() else {
+                return Ok(());
+            };
+            let enabled = encrypted.enable.is_some_and(std::convert::identity);
+            let marker_set = encrypted.markers(&pack_name)?;
+            let files_re = encrypted.files_regex(&pack_name)?;
+            let ignore_re = config.ignore_regex()?;
+
+            let mut wrapped_count = 0_usize;
+            for path in pack_files(pack, ignore_re.as_ref()) {
+                // 整文件加密的文件在仓库中应为密文，尚未加密时整个文件都是明文
+                let rel_path = path.strip_prefix(pack).unwrap_or(&path);
+                if enabled && config::is_whole_file(rel_path, files_re.as_ref()) {
+                    if !crypto::is_encrypted_file(&std::fs::read(&path)?) {
+                        println!("{}: whole file not encrypted", path.display());
+                        left_count += 1;
+                    }
+                    continue;
+                }
+                if !is_text(&path) {
+                    continue;
+                }
+                let Ok(content) = std::fs::read_to_string(&path) else {
+                    continue;
+                };
+                let markers = marker_set.get(rel_path);
+                let findings = secret_scan::scan(&content, markers);
+                if findings.is_empty() {
+                    continue;
+                }
+                for finding in &findings {
+                    println!(
+                        "{}:{}: {}",
+                        path.display(),
+                        finding.line(&content),
+                        finding.kind
+                    );
+                }
+                if !(fix && enabled) {
+                    left_count += findings.len();
+                    continue;
+                }
+                let wrappable = findings
+                    .iter()
+                    .filter(|it| it.wrappable(&content, markers))
+                    .count();
+                left_count += findings.len() - wrappable;
+                if wrappable > 0 {
+                    let temp =
+                        util::stage_write(&path, secret_scan::wrap(&content, &findings, markers))?;
+                    std::fs::rename(&temp, &path)?;
+                    info!("wrap {wrappable} secret(s) in {}", path.display());
+                    wrapped_count += wrappable;
+                }
+            }
+            if fix && !enabled {
+                warn!("encryption is not enabled, nothing is wrapped");
+            }
+            if wrapped_count > 0 {
+                info!("wrapped {wrapped_count} secret(s), run `stow-cm encrypt` to seal them");
+            }
+            Ok(())
+        })?;
+    }
+
+    if left_count > 0 {
+        bail!("{left_count} possible plaintext secret(s) found");
+    }
+    info!("no plaintext secret found");
+    Ok(())
+}
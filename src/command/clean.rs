@@ -7,34 +7,35 @@ use log::{debug, info, warn};
 
 use crate::config::Config;
 use crate::error::Result;
+use crate::symlink::Symlink;
 use crate::util;
 
-use super::{pack_envs, resolve_track_file};
+use super::hook::{Hooks, Op};
+use super::resolve_track_file;
 
 /// clean packages
 pub fn clean<P: AsRef<Path>>(config: &Arc<Config>, pack: P) -> Result<()> {
     let pack = Arc::new(pack.as_ref().to_path_buf());
-    let pack_name = config.resolve_pack_name(&pack)?.into_owned();
+    let mut hooks = Hooks::new(config, &pack, Op::Clean)?;
     info!("cleaning");
 
-    clean_link(config, &pack)?;
+    hooks.run("pre_remove", config.pre_remove.as_ref())?;
+    let links = clean_link(config, &pack)?;
+    hooks.set_links(&links)?;
 
     // execute the clear script
-    if let Some(command) = &config.clear {
-        info!("running clear script");
-        command.execute(&*pack, pack_envs(&pack, &pack_name))?;
-        info!("clear script done");
-    }
+    hooks.run_script("clear", config.clear.as_ref())?;
+    hooks.run("post_remove", config.post_remove.as_ref())?;
 
     Ok(())
 }
 
-/// clean links
-fn clean_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
+/// clean links, return the removed links
+fn clean_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<Vec<Symlink>> {
     let pack_name = config.resolve_pack_name(pack.as_ref())?.into_owned();
     let Some(target) = config.target.as_ref() else {
         warn!("target is none, skip clean links");
-        return Ok(());
+        return Ok(Vec::new());
     };
     let symlinks = util::find_prefix_symlink(target, pack.as_ref())?;
 
@@ -59,7 +60,7 @@ fn clean_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
             .ok_or_else(|| anyhow!("{pack_name}: decrypted path is not configured"))?;
         if decrypted_path.try_exists()? {
             info!("clean decrypted dir, {}", decrypted_path.display());
-            std::fs::remove_dir_all(decrypted_path)?;
+            util::shred_dir_all(decrypted_path)?;
         }
     }
 
@@ -70,5 +71,5 @@ fn clean_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
         std::fs::remove_file(track_file)?;
     }
 
-    Ok(())
+    Ok(symlinks)
 }
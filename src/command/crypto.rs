@@ -1,28 +1,86 @@
 use std::ops::Not;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::{Context, anyhow};
+use anyhow::{Context, anyhow, bail};
 use log::{debug, info, warn};
+use regex::RegexSet;
 use walkdir::WalkDir;
 
-use crate::config::{Config, EncryptedParams};
+use crate::config::{self, Config, EncryptedParams, Markers};
+use crate::constants::CONFIG_FILE_NAME;
 use crate::crypto;
 use crate::error::Result;
+use crate::util;
 
-type CryptoFn = fn(&str, &str, &[u8], &str, &str, bool) -> crate::error::Result<String>;
+use super::key::{block_path, sealed_files};
+
+type CryptoFn = fn(&str, &EncryptedParams<'_>, &Path) -> crate::error::Result<String>;
+
+/// 整文件加解密，返回 `None` 表示文件已处于目标状态，无需写入
+type FileCryptoFn = fn(&[u8], &EncryptedParams<'_>, &Path) -> crate::error::Result<Option<Vec<u8>>>;
+
+/// encrypt / decrypt 各自的处理方式
+struct CryptoOp {
+    name: &'static str,
+    /// `--dry-run` 输出中对块的动作
+    verb: &'static str,
+    content_label: &'static str,
+    inline: CryptoFn,
+    whole: FileCryptoFn,
+    /// 内联文件中待处理的块数
+    count: fn(&str, Markers<'_>) -> usize,
+    /// 整文件加密的文件是否待处理
+    whole_pending: fn(&[u8]) -> bool,
+}
+
+const ENCRYPT: CryptoOp = CryptoOp {
+    name: "encrypt",
+    verb: "seal",
+    content_label: "encrypted_content",
+    inline: crypto::encrypt_inline,
+    whole: encrypt_whole_file,
+    count: |content, markers| {
+        util::var_spans(content, markers.plain_left, markers.plain_right).len()
+    },
+    whole_pending: |content| !crypto::is_encrypted_file(content),
+};
+
+const DECRYPT: CryptoOp = CryptoOp {
+    name: "decrypt",
+    verb: "open",
+    content_label: "decrypted_content",
+    inline: |content, params, path| crypto::decrypt_inline(content, params, path, false),
+    whole: decrypt_whole_file,
+    // vault 引用保持原样
+    count: |content, markers| {
+        util::var_spans(content, markers.left, markers.right)
+            .iter()
+            .filter(|span| crypto::secret_ref(span.inner).is_none())
+            .count()
+    },
+    whole_pending: crypto::is_encrypted_file,
+};
+
+/// encrypt / decrypt 的命令行选项
+#[derive(Debug, Default)]
+pub struct CryptoOptions {
+    /// 仅输出每个文件待处理的块数，不写入
+    pub dry_run: bool,
+    /// 仅处理这些文件（绝对路径），为空时处理整个 pack
+    pub files: Vec<PathBuf>,
+}
 
 /// 提取 encrypt/decrypt 共享的加密配置参数，执行文件扫描和流式处理。
-fn crypto_process<P: AsRef<Path>>(
+/// 文件经同目录临时文件 rename 原子替换并沿用原权限，内容未变的文件不写入
+fn crypto_process(
     config: &Arc<Config>,
-    pack: P,
-    crypto_fn: CryptoFn,
-    op_name: &str,
-    content_label: &str,
+    pack: &Path,
+    op: &CryptoOp,
+    options: &CryptoOptions,
 ) -> Result<()> {
-    let pack = Arc::new(pack.as_ref().to_path_buf());
-    let pack_name = config.resolve_pack_name(&pack)?.into_owned();
-    info!("{op_name}");
+    let pack_name = config.resolve_pack_name(pack)?.into_owned();
+    info!("{}", op.name);
 
     let enabled = config
         .encrypted
@@ -34,28 +92,133 @@ fn crypto_process<P: AsRef<Path>>(
         return Ok(());
     }
 
-    let params = config
+    let encrypted = config
         .encrypted
         .as_ref()
-        .ok_or_else(|| anyhow!("{pack_name}: encrypted config not found"))?
-        .resolve(&pack_name)?;
-    let EncryptedParams {
-        key,
-        left_boundary,
-        right_boundary,
-        encrypted_alg,
-    } = params;
-    let key = key.as_slice();
+        .ok_or_else(|| anyhow!("{pack_name}: encrypted config not found"))?;
+    // dry-run 只数块，不需要密钥
+    let params = if options.dry_run {
+        encrypted.resolve_without_key(&pack_name)?
+    } else {
+        encrypted.resolve(&pack_name)?
+    };
 
     let ignore_re = config.ignore_regex()?;
 
-    // walk file, expect ignore_re, skip binary file
-    let files: Vec<_> = WalkDir::new(&*pack)
+    let files = selected_files(pack, ignore_re.as_ref(), &options.files);
+
+    debug!("{} paths {files:?}", op.name);
+    let mut block_count = 0_usize;
+    let mut file_count = 0_usize;
+    for path in &files {
+        let rel_path = path.strip_prefix(pack).unwrap_or(path);
+        let (content, processed) = if params.is_whole_file(rel_path) {
+            let content = std::fs::read(path)?;
+            if !(op.whole_pending)(&content) {
+                continue;
+            }
+            if options.dry_run {
+                println!("{}: whole file to {}", path.display(), op.verb);
+                block_count += 1;
+                file_count += 1;
+                continue;
+            }
+            let Some(processed) = (op.whole)(&content, &params, rel_path)
+                .with_context(|| format!("{pack_name}: path={}", path.display()))?
+            else {
+                continue;
+            };
+            info!("{} whole file {}", op.name, path.display());
+            (content, processed)
+        } else {
+            if !is_text(path) {
+                continue;
+            }
+            let Ok(content) = std::fs::read_to_string(path) else {
+                warn!("{} contains not invalid utf-8", path.display());
+                continue;
+            };
+            if options.dry_run {
+                let count = (op.count)(&content, params.markers(rel_path));
+                if count > 0 {
+                    println!("{}: {count} block(s) to {}", path.display(), op.verb);
+                    block_count += count;
+                    file_count += 1;
+                }
+                continue;
+            }
+            info!("{} {}", op.name, path.display());
+            let processed = (op.inline)(&content, &params, rel_path)
+                .with_context(|| format!("{pack_name}: path={}", path.display()))?
+                .into_bytes();
+            (content.into_bytes(), processed)
+        };
+        if processed == content {
+            continue;
+        }
+        let temp = util::stage_write(path, processed)?;
+        std::fs::rename(&temp, path).with_context(|| {
+            format!(
+                "{pack_name}: failed to write {} to path={}",
+                op.content_label,
+                path.display()
+            )
+        })?;
+    }
+
+    if options.dry_run {
+        info!(
+            "{block_count} block(s) in {file_count} file(s) would be {}ed",
+            op.verb
+        );
+    }
+    Ok(())
+}
+
+/// 待处理的文件：`files` 为空时为 pack 下的所有文件，否则为其中位于 pack 内的文件
+/// （其余属于同时处理的其他 pack，不在任何 pack 内的已在解析参数时报错）
+fn selected_files(pack: &Path, ignore_re: Option<&RegexSet>, files: &[PathBuf]) -> Vec<PathBuf> {
+    if files.is_empty() {
+        return pack_files(pack, ignore_re);
+    }
+    files
+        .iter()
+        .filter(|path| path.starts_with(pack))
+        .filter(|path| {
+            let ignored = ignore_re.is_some_and(|re| re.is_match(&path.to_string_lossy()));
+            if ignored {
+                warn!("{} is ignored, skip", path.display());
+            }
+            !ignored
+        })
+        .cloned()
+        .collect()
+}
+
+/// `--file` 所在的 pack：向上查找最近的含 `stow-cm.toml` 的目录，去重后按出现顺序返回
+pub fn file_packs(files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut packs: Vec<PathBuf> = Vec::new();
+    for file in files {
+        let pack = file
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(CONFIG_FILE_NAME).is_file())
+            .ok_or_else(|| anyhow!("{} is not in a pack", file.display()))?;
+        if !packs.iter().any(|it| it == pack) {
+            packs.push(pack.to_path_buf());
+        }
+    }
+    Ok(packs)
+}
+
+/// 遍历 pack 下的所有文件：跳过 `ignore_re` 匹配的路径
+pub(super) fn pack_files(pack: &Path, ignore_re: Option<&RegexSet>) -> Vec<PathBuf> {
+    WalkDir::new(pack)
         .into_iter()
         .filter_map(|entry| {
             let entry = entry.ok()?;
             let path = entry.path();
-            let ignore = match ignore_re.as_ref() {
+            let ignore = match ignore_re {
                 Some(ignore_re) => path
                     .to_str()
                     .is_some_and(|path_name| ignore_re.is_match(path_name)),
@@ -65,61 +228,176 @@ fn crypto_process<P: AsRef<Path>>(
                 return None;
             }
             if path.is_file() {
-                return Some(entry);
+                return Some(entry.into_path());
             }
             None
         })
-        .filter(|entry| {
-            let a = entry.path();
-            binaryornot::is_binary(a).is_ok_and(Not::not)
-        })
-        .collect();
-
-    debug!("{op_name} paths {files:?}");
-    for file in &files {
-        let path = file.path();
-        info!("{op_name} {}", path.display());
-        let Ok(content) = std::fs::read_to_string(path) else {
-            warn!("{} contains not invalid utf-8", path.display());
-            continue;
-        };
-        let processed = crypto_fn(
-            &content,
-            encrypted_alg,
-            key,
-            left_boundary,
-            right_boundary,
-            false,
-        )?;
-        std::fs::write(path, processed).with_context(|| {
-            format!(
-                "{pack_name}: failed to write {content_label} to path={}",
-                path.display()
-            )
-        })?;
+        .collect()
+}
+
+/// 遍历 pack 下的文本文件：在 [`pack_files`] 基础上跳过二进制文件
+pub(super) fn text_files(pack: &Path, ignore_re: Option<&RegexSet>) -> Vec<PathBuf> {
+    pack_files(pack, ignore_re)
+        .into_iter()
+        .filter(|path| is_text(path))
+        .collect()
+}
+
+pub(super) fn is_text(path: &Path) -> bool {
+    binaryornot::is_binary(path).is_ok_and(Not::not)
+}
+
+fn encrypt_whole_file(
+    content: &[u8],
+    params: &EncryptedParams<'_>,
+    rel_path: &Path,
+) -> Result<Option<Vec<u8>>> {
+    if crypto::is_encrypted_file(content) {
+        return Ok(None);
     }
+    Ok(Some(
+        crypto::encrypt_file(content, params, rel_path)?.into_bytes(),
+    ))
+}
 
-    Ok(())
+fn decrypt_whole_file(
+    content: &[u8],
+    params: &EncryptedParams<'_>,
+    rel_path: &Path,
+) -> Result<Option<Vec<u8>>> {
+    if !crypto::is_encrypted_file(content) {
+        return Ok(None);
+    }
+    Ok(Some(crypto::decrypt_file(
+        std::str::from_utf8(content)?,
+        params,
+        rel_path,
+    )?))
 }
 
 /// encrypt packages
-pub fn encrypt<P: AsRef<Path>>(config: &Arc<Config>, pack: P) -> Result<()> {
-    crypto_process(
-        config,
-        pack,
-        crypto::encrypt_inline,
-        "encrypt",
-        "encrypted_content",
-    )
+pub fn encrypt<P: AsRef<Path>>(
+    config: &Arc<Config>,
+    pack: P,
+    options: &CryptoOptions,
+) -> Result<()> {
+    crypto_process(config, pack.as_ref(), &ENCRYPT, options)
 }
 
 /// decrypt packages
-pub fn decrypt<P: AsRef<Path>>(config: &Arc<Config>, pack: P) -> Result<()> {
-    crypto_process(
-        config,
-        pack,
-        crypto::decrypt_inline,
-        "decrypt",
-        "decrypted_content",
-    )
+pub fn decrypt<P: AsRef<Path>>(
+    config: &Arc<Config>,
+    pack: P,
+    options: &CryptoOptions,
+) -> Result<()> {
+    crypto_process(config, pack.as_ref(), &DECRYPT, options)
+}
+
+/// 检查 pack 中是否残留明文块（或未加密的整文件），逐个输出 `path:line`，存在则返回错误
+pub fn encrypt_check<P: AsRef<Path>>(
+    config: &Arc<Config>,
+    pack: P,
+    options: &CryptoOptions,
+) -> Result<()> {
+    let pack = pack.as_ref();
+    let pack_name = config.resolve_pack_name(pack)?.into_owned();
+
+    let Some(encrypted) = config
+        .encrypted
+        .as_ref()
+        .filter(|it| it.enable.is_some_and(std::convert::identity))
+    else {
+        warn!("pack is not enable encrypted");
+        return Ok(());
+    };
+    let marker_set = encrypted.markers(&pack_name)?;
+    let files_re = encrypted.files_regex(&pack_name)?;
+    let ignore_re = config.ignore_regex()?;
+
+    let mut left_count = 0_usize;
+    for path in selected_files(pack, ignore_re.as_ref(), &options.files) {
+        let rel_path = path.strip_prefix(pack).unwrap_or(&path);
+        if config::is_whole_file(rel_path, files_re.as_ref()) {
+            if !crypto::is_encrypted_file(&std::fs::read(&path)?) {
+                println!("{}: not encrypted", path.display());
+                left_count += 1;
+            }
+            continue;
+        }
+        if !is_text(&path) {
+            continue;
+        }
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let markers = marker_set.get(path.strip_prefix(pack).unwrap_or(&path));
+        for span in util::var_spans(&content, markers.plain_left, markers.plain_right) {
+            println!("{}:{}", path.display(), span.line(&content));
+            left_count += 1;
+        }
+    }
+
+    if left_count > 0 {
+        bail!("{pack_name}: {left_count} plaintext block(s) left, run `stow-cm encrypt` first");
+    }
+    info!("no plaintext block left");
+    Ok(())
+}
+
+/// 尝试解密 pack 中的每个加密块（含整文件加密的文件与 vault 引用），不写入任何文件；
+/// 逐个输出失败的 `path:line` 与原因，存在失败则返回错误
+pub fn verify<P: AsRef<Path>>(config: &Arc<Config>, pack: P) -> Result<()> {
+    let pack = pack.as_ref();
+    let pack_name = config.resolve_pack_name(pack)?.into_owned();
+
+    let Some(encrypted) = config
+        .encrypted
+        .as_ref()
+        .filter(|it| it.enable.is_some_and(std::convert::identity))
+    else {
+        warn!("pack is not enable encrypted");
+        return Ok(());
+    };
+    let params = encrypted.resolve(&pack_name)?;
+    let ignore_re = config.ignore_regex()?;
+
+    let mut block_count = 0_usize;
+    let mut failed_count = 0_usize;
+    for path in sealed_files(pack, ignore_re.as_ref(), &params) {
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let rel_path = block_path(pack, &path, &params);
+        if crypto::is_encrypted_file(content.as_bytes()) {
+            block_count += 1;
+            if let Err(e) = crypto::decrypt_file(&content, &params, rel_path) {
+                println!("{}: {e:#}", path.display());
+                failed_count += 1;
+            }
+            continue;
+        }
+        let markers = params.markers(rel_path);
+        for span in util::var_spans(&content, markers.left, markers.right) {
+            block_count += 1;
+            let opened = match crypto::secret_ref(span.inner) {
+                Some(name) => params.secret(name).map(drop),
+                None => crypto::decrypt_block(
+                    span.inner,
+                    &params,
+                    &crypto::site_aad(rel_path, span.label, &params),
+                )
+                .map(drop),
+            };
+            if let Err(e) = opened {
+                println!("{}:{}: {e:#}", path.display(), span.line(&content));
+                failed_count += 1;
+            }
+        }
+    }
+
+    if failed_count > 0 {
+        bail!("{pack_name}: {failed_count} of {block_count} block(s) failed to decrypt");
+    }
+    info!("{block_count} block(s) verified");
+    Ok(())
 }
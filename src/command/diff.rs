@@ -0,0 +1,149 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use similar::TextDiff;
+use walkdir::WalkDir;
+
+use crate::config::Config;
+use crate::crypto;
+use crate::error::Result;
+use crate::symlink::SymlinkMode;
+use crate::track_file::Track;
+use crate::util;
+
+use super::install::decrypt_content;
+use super::{installed_pack_paths, resolve_track_file};
+
+/// 对比 target 中已安装的派生文件（copy 模式副本、解密文件）与 pack 当前应生成的内容，
+/// 以 unified diff 形式输出差异。
+///
+/// 不传 `paths` 则检查所有已安装 pack。解密文件的期望内容由 pack 中的密文在内存中解密得到，
+/// 不写入任何文件。
+pub fn diff(global: &Config, paths: Vec<PathBuf>) -> Result<()> {
+    let paths = if paths.is_empty() {
+        installed_pack_paths()?
+    } else {
+        util::canonicalize(paths)?
+    };
+
+    let mut changed = 0_usize;
+    for pack in &paths {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        changed += util::scoped_log_prefix(&pack_name, || diff_pack(&config, pack, &pack_name))?;
+    }
+
+    if changed == 0 {
+        info!("no differences");
+    }
+    Ok(())
+}
+
+/// 输出单个 pack 的差异，返回存在差异的文件数
+fn diff_pack(config: &Config, pack: &Path, pack_name: &str) -> Result<usize> {
+    let track_file = resolve_track_file(pack, pack_name)?;
+    if !track_file.try_exists()? {
+        warn!("pack is not installed, skip diff");
+        return Ok(0);
+    }
+    let track: Track = toml::from_str(&std::fs::read_to_string(&track_file)?)?;
+
+    let params = match track.decrypted_path {
+        Some(_) => Some(
+            config
+                .encrypted
+                .as_ref()
+                .ok_or_else(|| anyhow!("{pack_name}: encrypted config not found"))?
+                .resolve(pack_name)?,
+        ),
+        None => None,
+    };
+
+    let mut changed = 0_usize;
+    for link in &track.links {
+        let decrypted = track
+            .decrypted_path
+            .as_ref()
+            .is_some_and(|decrypted| link.src.starts_with(decrypted));
+        // 直接指向 pack 的 symlink 与源文件是同一文件，不存在差异
+        if !decrypted && link.mode == SymlinkMode::Symlink {
+            continue;
+        }
+        // symlink 模式下应用修改的是解密文件本身，copy 模式下修改的是 target 中的副本
+        let installed_root = match link.mode {
+            SymlinkMode::Symlink => &link.src,
+            SymlinkMode::Copy => &link.dst,
+        };
+        let source_root = track.pack_source(link);
+
+        for (source, installed) in derived_files(&source_root, installed_root)? {
+            let expected = match params.as_ref().filter(|_| decrypted) {
+                Some(params) => decrypt_content(pack, &source, params)?,
+                None => std::fs::read(&source)?,
+            };
+            let actual = match std::fs::read(&installed) {
+                Ok(actual) => actual,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    warn!("missing installed file {}", installed.display());
+                    changed += 1;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if print_diff(&installed, &source, &actual, &expected) {
+                changed += 1;
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// 将链接源（文件或折叠目录）展开为 `(pack 源文件, 已安装文件)` 对
+fn derived_files(source_root: &Path, installed_root: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if !source_root.is_dir() {
+        return Ok(vec![(
+            source_root.to_path_buf(),
+            installed_root.to_path_buf(),
+        )]);
+    }
+    WalkDir::new(source_root)
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            let installed = crypto::decrypted_file_path(&util::change_base_path(
+                entry.path(),
+                source_root,
+                installed_root,
+            )?);
+            Ok((entry.into_path(), installed))
+        })
+        .collect()
+}
+
+/// 打印 `installed` 到 `expected` 的 unified diff，返回两者是否存在差异
+fn print_diff(installed: &Path, source: &Path, actual: &[u8], expected: &[u8]) -> bool {
+    if actual == expected {
+        return false;
+    }
+    let (Ok(actual), Ok(expected)) = (std::str::from_utf8(actual), std::str::from_utf8(expected))
+    else {
+        println!(
+            "Binary files {} and {} differ",
+            installed.display(),
+            source.display()
+        );
+        return true;
+    };
+    let installed_label = installed.to_string_lossy();
+    let source_label = source.to_string_lossy();
+    print!(
+        "{}",
+        TextDiff::from_lines(actual, expected)
+            .unified_diff()
+            .context_radius(3)
+            .header(&installed_label, &source_label)
+    );
+    true
+}
@@ -0,0 +1,247 @@
+use std::fs::OpenOptions;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, anyhow, bail};
+use log::{error, info};
+
+use crate::config::{Config, EncryptedParams};
+use crate::crypto;
+use crate::error::Result;
+use crate::util;
+use crate::vault;
+
+use super::filter::FilterPack;
+use super::key::block_path;
+
+/// 输出 pack 文件解密后的内容，即安装后的内容
+pub fn cat(global: &Config, path: &Path) -> Result<()> {
+    let (filter_pack, path) = locate(global, path)?;
+    let params = resolve(&filter_pack)?;
+    let rel_path = block_path(&filter_pack.pack, &path, &params);
+    let content = std::fs::read(&path)?;
+    let plain = if is_whole(&params, rel_path, &content) {
+        open_whole(content, &params, rel_path)?
+    } else {
+        crypto::decrypt_inline(&String::from_utf8(content)?, &params, rel_path, true)?.into_bytes()
+    };
+    std::io::stdout().write_all(&plain)?;
+    Ok(())
+}
+
+/// 解密 pack 文件到私有临时目录（优先 `$XDG_RUNTIME_DIR`）并用 `$VISUAL`/`$EDITOR` 打开，
+/// 保存后重新加密写回；内容未变的块沿用原密文。
+///
+/// 编辑后的内容无法解析（未闭合的块、被改动的密文、不存在的 secret、vault 不是合法 TOML）时拒绝写回，
+/// 终端中可选择重新编辑。临时目录（含编辑器的交换文件）结束时覆写后删除。
+pub fn edit(global: &Config, path: &Path) -> Result<()> {
+    let (filter_pack, path) = locate(global, path)?;
+    let params = resolve(&filter_pack)?;
+    let rel_path = block_path(&filter_pack.pack, &path, &params);
+    let content = std::fs::read(&path)?;
+    let whole = is_whole(&params, rel_path, &content);
+    let original = if whole {
+        String::new()
+    } else {
+        String::from_utf8(content.clone())?
+    };
+    let plain = if whole {
+        open_whole(content, &params, rel_path)?
+    } else {
+        crypto::decrypt_inline(&original, &params, rel_path, false)?.into_bytes()
+    };
+
+    let dir = dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("stow-cm-edit-{}", std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    let edited = edit_in(&dir, &path, &plain, |edited| {
+        if whole {
+            seal_whole(edited, &params, rel_path, vault::is_vault(&path, &params))
+        } else {
+            seal_inline(edited, &original, &params, rel_path)
+        }
+    });
+    util::shred_dir_all(&dir)?;
+
+    let Some(sealed) = edited? else {
+        info!("no changes to {}", path.display());
+        return Ok(());
+    };
+    let temp = util::stage_write(&path, sealed)?;
+    std::fs::rename(&temp, &path).with_context(|| format!("failed to write {}", path.display()))?;
+    info!("encrypt {}", path.display());
+    Ok(())
+}
+
+/// 文件所属的加密 pack 与文件的绝对路径
+fn locate(global: &Config, path: &Path) -> Result<(FilterPack, PathBuf)> {
+    let path = std::fs::canonicalize(path).with_context(|| format!("path={}", path.display()))?;
+    let filter_pack = FilterPack::locate(global, &path)?.ok_or_else(|| {
+        anyhow!(
+            "{} is not in a pack with encryption enabled",
+            path.display()
+        )
+    })?;
+    Ok((filter_pack, path))
+}
+
+fn resolve(filter_pack: &FilterPack) -> Result<EncryptedParams<'_>> {
+    filter_pack
+        .encrypted()
+        .ok_or_else(|| anyhow!("{}: encrypted config not found", filter_pack.pack_name))?
+        .resolve(&filter_pack.pack_name)
+}
+
+fn is_whole(params: &EncryptedParams<'_>, rel_path: &Path, content: &[u8]) -> bool {
+    params.is_whole_file(rel_path) || crypto::is_encrypted_file(content)
+}
+
+/// 整文件加密的内容；尚未加密的文件原样返回
+fn open_whole(content: Vec<u8>, params: &EncryptedParams<'_>, rel_path: &Path) -> Result<Vec<u8>> {
+    if !crypto::is_encrypted_file(&content) {
+        return Ok(content);
+    }
+    crypto::decrypt_file(&String::from_utf8(content)?, params, rel_path)
+}
+
+/// 在 `dir` 中编辑 `plain`，返回 `seal` 后的新内容；内容未变时返回 `None`
+fn edit_in(
+    dir: &Path,
+    path: &Path,
+    plain: &[u8],
+    seal: impl Fn(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    // 沿用原文件名（去掉 `.enc`），便于编辑器识别文件类型
+    let file = dir.join(
+        crypto::decrypted_file_path(path)
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid file name {}", path.display()))?,
+    );
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&file)
+        .and_then(|mut it| it.write_all(plain))
+        .with_context(|| format!("failed to write {}", file.display()))?;
+
+    loop {
+        run_editor(&file)?;
+        let edited = std::fs::read(&file)?;
+        if edited == plain {
+            return Ok(None);
+        }
+        match seal(&edited) {
+            Ok(sealed) => return Ok(Some(sealed)),
+            Err(e) => {
+                error!("{e:#}");
+                let again = std::io::stdin().is_terminal()
+                    && inquire::Confirm::new("edit again?")
+                        .with_default(true)
+                        .prompt()
+                        .unwrap_or(false);
+                if !again {
+                    return Err(e.context(format!("{} is not changed", path.display())));
+                }
+            }
+        }
+    }
+}
+
+fn run_editor(file: &Path) -> Result<()> {
+    let editor = ["VISUAL", "EDITOR"]
+        .into_iter()
+        .find_map(|name| std::env::var(name).ok().filter(|it| !it.trim().is_empty()))
+        .unwrap_or_else(|| "vi".to_owned());
+    // 经 shell 执行以支持带参数的编辑器，如 `code --wait`
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(file)
+        .status()
+        .with_context(|| format!("failed to run {editor}"))?;
+    if !status.success() {
+        bail!("{editor} exited with {status}");
+    }
+    Ok(())
+}
+
+fn seal_whole(
+    edited: &[u8],
+    params: &EncryptedParams<'_>,
+    rel_path: &Path,
+    is_vault: bool,
+) -> Result<Vec<u8>> {
+    if is_vault {
+        toml::from_str::<vault::Secrets>(std::str::from_utf8(edited)?)
+            .context("vault is not a valid TOML table of strings")?;
+    }
+    Ok(crypto::encrypt_file(edited, params, rel_path)?.into_bytes())
+}
+
+fn seal_inline(
+    edited: &[u8],
+    original: &str,
+    params: &EncryptedParams<'_>,
+    rel_path: &Path,
+) -> Result<Vec<u8>> {
+    let edited = std::str::from_utf8(edited).context("edited file is not valid UTF-8")?;
+    let markers = params.markers(rel_path);
+    for (left, right) in [
+        (markers.plain_left, markers.plain_right),
+        (markers.left, markers.right),
+    ] {
+        if let Some(line) = unclosed_line(edited, left, right) {
+            bail!("line {line}: unclosed `{left}`");
+        }
+    }
+    // vault 引用须指向已有的 secret
+    for span in util::var_spans(edited, markers.left, markers.right) {
+        if let Some(name) = crypto::secret_ref(span.inner) {
+            params
+                .secret(name)
+                .with_context(|| format!("line {}", span.line(edited)))?;
+        }
+    }
+    let sealed = crypto::encrypt_inline_reusing(edited, params, rel_path, original)?;
+    // 被改动的密文块无法解密
+    crypto::decrypt_inline(&sealed, params, rel_path, false).context("invalid encrypted block")?;
+    Ok(sealed.into_bytes())
+}
+
+/// 第一个未闭合（且未转义）的 `left` 所在的行号
+fn unclosed_line(content: &str, left: &str, right: &str) -> Option<usize> {
+    let spans = util::var_spans(content, left, right);
+    content
+        .match_indices(left)
+        .map(|(start, _)| start)
+        .filter(|&start| !util::is_escaped(content, start))
+        .find(|start| !spans.iter().any(|span| span.range.start == *start))
+        .map(|start| {
+            content
+                .get(..start)
+                .map_or(0, |prefix| prefix.matches('\n').count())
+                + 1
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unclosed_line_test() {
+        assert_eq!(unclosed_line("a = &+{x}\nb = &+{y}\n", "&+{", "}"), None);
+        assert_eq!(unclosed_line("a = &+{x}\nb = &+{y\n", "&+{", "}"), Some(2));
+        assert_eq!(unclosed_line("a = &+{x &+{y}\n", "&+{", "}"), Some(1));
+        assert_eq!(unclosed_line("no blocks\n", "&+{", "}"), None);
+        assert_eq!(unclosed_line("a = \\&+{x\n", "&+{", "}"), None);
+    }
+}
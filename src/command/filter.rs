@@ -0,0 +1,427 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, anyhow, bail};
+use log::{debug, info, warn};
+
+use crate::config::{Config, EncryptedConfig, EncryptedParams};
+use crate::constants::CONFIG_FILE_NAME;
+use crate::crypto;
+use crate::error::Result;
+use crate::util;
+
+use super::crypto::pack_files;
+use super::install::has_encrypted_content;
+
+/// git filter driver 名称
+const FILTER_DRIVER: &str = "stow-cm";
+
+/// 文件所属的加密 pack，filter 与 edit/cat 共用
+pub(super) struct FilterPack {
+    pub(super) pack: PathBuf,
+    pub(super) pack_name: String,
+    config: Config,
+}
+
+impl FilterPack {
+    /// 从文件路径向上查找包含 `stow-cm.toml` 的 pack；不在 pack 中或 pack 未启用加密时返回 `None`
+    pub(super) fn locate(global: &Config, path: &Path) -> Result<Option<Self>> {
+        let path = std::path::absolute(path)?;
+        let Some(pack) = path
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(CONFIG_FILE_NAME).is_file())
+        else {
+            return Ok(None);
+        };
+        let filter_pack = Self::load(global, pack)?;
+        Ok(filter_pack.encrypted().is_some().then_some(filter_pack))
+    }
+
+    fn load(global: &Config, pack: &Path) -> Result<Self> {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        Ok(Self {
+            pack: pack.to_path_buf(),
+            pack_name,
+            config,
+        })
+    }
+
+    pub(super) fn encrypted(&self) -> Option<&EncryptedConfig> {
+        self.config
+            .encrypted
+            .as_ref()
+            .filter(|it| it.enable.is_some_and(std::convert::identity))
+    }
+}
+
+/// git clean filter：加密 stdin 中的明文块后写到 stdout。
+///
+/// 内容与位置未变的块沿用暂存区中已有的密文，避免每次 `git status` 都产生新的密文；
+/// 失败时返回错误，由 git 中止提交（`filter.stow-cm.required`），不会提交明文。
+pub fn filter_clean(global: &Config, path: &Path) -> Result<()> {
+    let content = read_stdin()?;
+    let Some(filter_pack) = FilterPack::locate(global, path)? else {
+        return write_stdout(&content);
+    };
+    let encrypted = filter_pack
+        .encrypted()
+        .ok_or_else(|| anyhow!("{}: encrypted config not found", filter_pack.pack_name))?;
+    let abs_path = std::path::absolute(path)?;
+    let rel_path = abs_path.strip_prefix(&filter_pack.pack)?;
+    let whole_file = crate::config::is_whole_file(
+        rel_path,
+        encrypted.files_regex(&filter_pack.pack_name)?.as_ref(),
+    );
+    let markers = encrypted.markers(&filter_pack.pack_name)?.get(rel_path);
+
+    // 非文本文件或不含明文块时无需加密
+    let text = std::str::from_utf8(&content).ok();
+    let sealed = text.is_none_or(|text| !text.contains(markers.plain_left));
+    if crypto::is_encrypted_file(&content) || (!whole_file && sealed) {
+        return write_stdout(&content);
+    }
+    let params = encrypted.resolve(&filter_pack.pack_name)?;
+    let previous = index_blob(path);
+
+    let processed = if whole_file {
+        let unchanged = previous
+            .as_deref()
+            .filter(|previous| crypto::is_encrypted_file(previous))
+            .and_then(|previous| std::str::from_utf8(previous).ok())
+            .filter(|previous| {
+                crypto::decrypt_file(previous, &params, rel_path).is_ok_and(|it| it == content)
+            });
+        match unchanged {
+            Some(previous) => previous.to_owned(),
+            None => crypto::encrypt_file(&content, &params, rel_path)?,
+        }
+    } else {
+        let previous = previous
+            .as_deref()
+            .and_then(|previous| std::str::from_utf8(previous).ok())
+            .unwrap_or_default();
+        crypto::encrypt_inline_reusing(text.unwrap_or_default(), &params, rel_path, previous)?
+    };
+    debug!("clean {}", path.display());
+    write_stdout(processed.as_bytes())
+        .with_context(|| format!("{}: path={}", filter_pack.pack_name, path.display()))
+}
+
+/// git smudge filter：解密 stdin 中的密文块为明文块后写到 stdout。
+///
+/// 解密失败（如本机没有密钥）时原样输出密文并警告，不阻塞 checkout。
+pub fn filter_smudge(global: &Config, path: &Path) -> Result<()> {
+    let content = read_stdin()?;
+    let Some(filter_pack) = FilterPack::locate(global, path)? else {
+        return write_stdout(&content);
+    };
+    let rel_path = std::path::absolute(path)?
+        .strip_prefix(&filter_pack.pack)?
+        .to_path_buf();
+    match open(&filter_pack, &content, &rel_path) {
+        Ok(Some(processed)) => write_stdout(&processed),
+        Ok(None) => write_stdout(&content),
+        Err(e) => {
+            warn!("keep ciphertext of {}: {e:#}", path.display());
+            write_stdout(&content)
+        }
+    }
+}
+
+/// git diff textconv：输出解密后的文件内容，无法解密的块保持密文。
+///
+/// git 不向 textconv 传入文件在仓库中的路径，`git setup` 为每个含密文的文件配置带 `--path` 的 diff driver，
+/// 用于匹配 `rules` 与解密绑定路径的块（`bind_blocks`）。未传入 `rel_path` 时只能处理工作区中的文件，
+/// 或不依赖路径的内容（未配置 `rules` 且块未绑定路径），否则报错而不猜测路径
+pub fn filter_textconv(
+    global: &Config,
+    pack: &Path,
+    rel_path: Option<&Path>,
+    file: &Path,
+) -> Result<()> {
+    let content = std::fs::read(file).with_context(|| format!("path={}", file.display()))?;
+    let filter_pack = FilterPack::load(global, pack)?;
+    let Some(encrypted) = filter_pack.encrypted() else {
+        return write_stdout(&content);
+    };
+    let params = encrypted.resolve(&filter_pack.pack_name)?;
+    let rel_path = match rel_path {
+        Some(rel_path) => Some(rel_path.to_path_buf()),
+        None => std::path::absolute(file)?
+            .strip_prefix(&filter_pack.pack)
+            .ok()
+            .map(Path::to_path_buf),
+    };
+    debug!("textconv {} as {rel_path:?}", file.display());
+    let text = std::str::from_utf8(&content).ok();
+    let rel_path = match rel_path {
+        Some(rel_path) => rel_path,
+        None if text.is_some_and(|text| !depends_on_path(text, &params)) => PathBuf::new(),
+        None => bail!(
+            "{}: cannot tell which file {} is, run `stow-cm git setup` again to add its diff driver",
+            filter_pack.pack_name,
+            file.display()
+        ),
+    };
+
+    let Some(text) = text else {
+        return write_stdout(&content);
+    };
+    if crypto::is_encrypted_file(&content) {
+        return write_stdout(&crypto::decrypt_file(text, &params, &rel_path)?);
+    }
+    let markers = params.markers(&rel_path);
+    let processed =
+        util::var_inplace_labeled(text, markers.left, markers.right, true, |block, label| {
+            let aad = crypto::site_aad(&rel_path, label, &params);
+            Ok(match crypto::decrypt_block(block, &params, &aad) {
+                Ok(opened) => format!("{}{opened}{}", markers.plain_left, markers.plain_right),
+                Err(_) => format!("{}{block}{}", markers.left, markers.right),
+            })
+        })?;
+    write_stdout(processed.as_bytes())
+}
+
+/// 解密是否依赖文件路径：配置了按路径选择分隔符的 `rules`，或含绑定路径的块
+fn depends_on_path(text: &str, params: &EncryptedParams<'_>) -> bool {
+    if !params.marker_set.rules.is_empty() {
+        return true;
+    }
+    if crypto::is_encrypted_file(text.as_bytes()) {
+        let mut bound = false;
+        let _ = crypto::map_file_block(text, |block| {
+            bound = crypto::is_bound_block(block);
+            Ok(String::new())
+        });
+        return bound;
+    }
+    let markers = params.markers(Path::new(""));
+    util::var_spans(text, markers.left, markers.right)
+        .iter()
+        .any(|span| crypto::is_bound_block(span.inner))
+}
+
+/// 将密文解密为工作区内容，无需解密时返回 `None`
+fn open(filter_pack: &FilterPack, content: &[u8], rel_path: &Path) -> Result<Option<Vec<u8>>> {
+    let Some(encrypted) = filter_pack.encrypted() else {
+        return Ok(None);
+    };
+    if crypto::is_encrypted_file(content) {
+        let params = encrypted.resolve(&filter_pack.pack_name)?;
+        return crypto::decrypt_file(std::str::from_utf8(content)?, &params, rel_path).map(Some);
+    }
+    let markers = encrypted.markers(&filter_pack.pack_name)?.get(rel_path);
+    let Some(text) = std::str::from_utf8(content)
+        .ok()
+        .filter(|text| text.contains(markers.left))
+    else {
+        return Ok(None);
+    };
+    let params = encrypted.resolve(&filter_pack.pack_name)?;
+    crypto::decrypt_inline(text, &params, rel_path, false).map(|it| Some(it.into_bytes()))
+}
+
+/// 暂存区中该文件的内容（git 在仓库根目录运行 filter，`path` 相对仓库根目录）
+fn index_blob(path: &Path) -> Option<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("cat-file")
+        .arg("blob")
+        .arg(format!(":{}", path.display()))
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+fn read_stdin() -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    std::io::stdin().read_to_end(&mut content)?;
+    Ok(content)
+}
+
+fn write_stdout(content: &[u8]) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(content)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// 为 pack 所在仓库写入 `.gitattributes` 与 git config，工作区保留明文、提交中只有密文。
+///
+/// - `.gitattributes`：pack 下的文件使用 `stow-cm` filter 与 pack 自己的 diff driver，
+///   含密文的文件各自使用带 `--path` 的 diff driver，`stow-cm.toml` 除外；已存在的行不会重复写入。
+/// - git config（仓库级）：`filter.stow-cm.{clean,smudge,required}` 与各 diff driver 的 `textconv`。
+///
+/// 新增含密文的文件后需再次运行。
+pub fn git_setup(global: &Config, packs: &[PathBuf]) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let exe = shell_quote(&exe.to_string_lossy());
+    for pack in packs {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        util::scoped_log_prefix(&pack_name, || -> Result<()> {
+            if !config
+                .encrypted
+                .as_ref()
+                .is_some_and(|it| it.enable.is_some_and(std::convert::identity))
+            {
+                warn!("pack is not enable encrypted");
+            }
+            let toplevel = PathBuf::from(git(pack, &["rev-parse", "--show-toplevel"])?.trim());
+            let toplevel = std::fs::canonicalize(&toplevel)
+                .with_context(|| format!("path: {}", toplevel.display()))?;
+            let rel_pack = pack
+                .strip_prefix(&toplevel)
+                .with_context(|| format!("{} is not in {}", pack.display(), toplevel.display()))?;
+
+            let diff_driver = format!("{FILTER_DRIVER}-{pack_name}");
+            let (files, config_file) = if rel_pack.as_os_str().is_empty() {
+                ("*".to_owned(), CONFIG_FILE_NAME.to_owned())
+            } else {
+                let rel_pack = rel_pack.to_string_lossy();
+                (
+                    format!("{rel_pack}/**"),
+                    format!("{rel_pack}/{CONFIG_FILE_NAME}"),
+                )
+            };
+            let pack_arg = shell_quote(&pack.to_string_lossy());
+            let mut attributes = vec![
+                format!("{files} filter={FILTER_DRIVER} diff={diff_driver}"),
+                format!("{config_file} -filter -diff"),
+            ];
+            let mut drivers = Vec::new();
+            for rel_path in sealed_paths(&config, pack, &pack_name)? {
+                let rel = rel_path.to_string_lossy();
+                if rel.contains(|c: char| c.is_whitespace() || c == '"') {
+                    warn!(
+                        "{rel}: cannot be written to .gitattributes, git diff shows its ciphertext"
+                    );
+                    continue;
+                }
+                let id: String = util::hash(&rel).chars().take(12).collect();
+                let driver = format!("{diff_driver}-{id}");
+                let pattern = glob_escape(&rel_pack.join(&rel_path).to_string_lossy());
+                attributes.push(format!("{pattern} filter={FILTER_DRIVER} diff={driver}"));
+                drivers.push((
+                    format!("diff.{driver}.textconv"),
+                    format!(
+                        "{exe} filter textconv --pack {pack_arg} --path {}",
+                        shell_quote(&rel)
+                    ),
+                ));
+            }
+            append_attributes(&toplevel.join(".gitattributes"), &attributes)?;
+
+            for (key, value) in [
+                (
+                    format!("filter.{FILTER_DRIVER}.clean"),
+                    format!("{exe} filter clean %f"),
+                ),
+                (
+                    format!("filter.{FILTER_DRIVER}.smudge"),
+                    format!("{exe} filter smudge %f"),
+                ),
+                (
+                    format!("filter.{FILTER_DRIVER}.required"),
+                    "true".to_owned(),
+                ),
+                (
+                    format!("diff.{diff_driver}.textconv"),
+                    format!("{exe} filter textconv --pack {pack_arg}"),
+                ),
+            ] {
+                git(&toplevel, &["config", "--local", &key, &value])?;
+            }
+            for (key, value) in drivers {
+                git(&toplevel, &["config", "--local", &key, &value])?;
+            }
+            info!(
+                "git filter set up in {}, run `stow-cm decrypt` to turn checked out ciphertext into plaintext",
+                toplevel.display()
+            );
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// pack 中含密文的文件（pack 内相对路径），与 install 经解密目录安装的文件一致
+fn sealed_paths(config: &Config, pack: &Path, pack_name: &str) -> Result<Vec<PathBuf>> {
+    let Some(encrypted) = config
+        .encrypted
+        .as_ref()
+        .filter(|it| it.enable.is_some_and(std::convert::identity))
+    else {
+        return Ok(Vec::new());
+    };
+    let params = encrypted.resolve_without_key(pack_name)?;
+    let ignore_re = config.ignore_regex()?;
+    Ok(pack_files(pack, ignore_re.as_ref())
+        .into_iter()
+        .filter(|path| has_encrypted_content(pack, path, &params))
+        .filter_map(|path| path.strip_prefix(pack).ok().map(Path::to_path_buf))
+        .filter(|rel_path| !rel_path.starts_with(".git"))
+        .collect())
+}
+
+/// gitattributes 中按字面匹配路径：转义 glob 特殊字符
+fn glob_escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 追加 `.gitattributes` 中尚不存在的行
+fn append_attributes(path: &Path, lines: &[String]) -> Result<()> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("path: {}", path.display())),
+    };
+    let mut content = existing.clone();
+    for line in lines {
+        if existing.lines().any(|it| it.trim() == line) {
+            continue;
+        }
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(line);
+        content.push('\n');
+    }
+    if content != existing {
+        std::fs::write(path, content).with_context(|| format!("path: {}", path.display()))?;
+        info!("updated {}", path.display());
+    }
+    Ok(())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// 单引号包裹，供 git 通过 shell 执行
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
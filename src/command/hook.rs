@@ -0,0 +1,175 @@
+use std::convert::identity;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, anyhow};
+use log::{debug, info};
+
+use crate::config::{Command, Config, Hook};
+use crate::constants::{
+    PACK_DECRYPTED_PATH_ENV, PACK_ID_ENV, PACK_NAME_ENV, PACK_PATH_ENV, PACK_TARGET_ENV,
+    STOW_CM_DRY_RUN_ENV, STOW_CM_LINKS_ENV, STOW_CM_OP_ENV,
+};
+use crate::error::Result;
+use crate::symlink::Symlink;
+use crate::util;
+
+/// 执行脚本与钩子的 pack 操作，导出为 `STOW_CM_OP`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Op {
+    Install,
+    Remove,
+    Reload,
+    Clean,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Install => "install",
+            Self::Remove => "remove",
+            Self::Reload => "reload",
+            Self::Clean => "clean",
+        }
+    }
+}
+
+/// init / clear 脚本与钩子的执行环境：pack 信息、当前操作，以及本次创建或删除的链接列表文件
+pub(super) struct Hooks<'a> {
+    config: &'a Config,
+    pack: &'a Path,
+    pack_name: String,
+    op: Op,
+    /// 每行一个链接路径，`set_links` 之后才存在
+    links_file: Option<PathBuf>,
+}
+
+impl<'a> Hooks<'a> {
+    pub(super) fn new(config: &'a Config, pack: &'a Path, op: Op) -> Result<Self> {
+        Ok(Hooks {
+            config,
+            pack,
+            pack_name: config.resolve_pack_name(pack)?.into_owned(),
+            op,
+            links_file: None,
+        })
+    }
+
+    /// 记录刚创建或删除的链接，之后执行的脚本经 `STOW_CM_LINKS` 读取。
+    ///
+    /// 列表文件可能位于共享的临时目录，每次都删除旧文件后以 `create_new` 与 0600 新建，
+    /// 不会跟随他人预先放置的符号链接覆写其他文件
+    pub(super) fn set_links(&mut self, links: &[Symlink]) -> Result<()> {
+        let path =
+            match &self.links_file {
+                Some(path) => {
+                    match std::fs::remove_file(path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                            return Err(e).with_context(|| format!("path: {}", path.display()));
+                        }
+                        _ => {}
+                    }
+                    path
+                }
+                None => self.links_file.insert(
+                    dirs::runtime_dir()
+                        .unwrap_or_else(std::env::temp_dir)
+                        .join(format!(
+                            "stow-cm-{}-{}.links",
+                            std::process::id(),
+                            util::hash(&self.pack.to_string_lossy())
+                        )),
+                ),
+            };
+        let content = links.iter().fold(String::new(), |mut content, link| {
+            let _ = writeln!(content, "{}", link.dst.display());
+            content
+        });
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// 执行 init / clear 脚本
+    pub(super) fn run_script(&self, name: &str, command: Option<&Command>) -> Result<()> {
+        let Some(command) = command else {
+            return Ok(());
+        };
+        info!("running {name} script");
+        self.execute(command)
+            .map_err(|e| anyhow!("{}: {name} script failed: {e:#}", self.pack_name))?;
+        info!("{name} script done");
+        Ok(())
+    }
+
+    /// 依次执行钩子列表中的命令，任一失败即返回错误
+    pub(super) fn run(&self, name: &str, hooks: Option<&Vec<Hook>>) -> Result<()> {
+        let commands: Vec<_> = hooks
+            .into_iter()
+            .flatten()
+            .filter_map(Hook::command)
+            .collect();
+        if commands.is_empty() {
+            return Ok(());
+        }
+        info!("running {name} hooks");
+        for command in commands {
+            self.execute(command)
+                .map_err(|e| anyhow!("{}: {name} hook failed: {e:#}", self.pack_name))?;
+        }
+        info!("{name} hooks done");
+        Ok(())
+    }
+
+    fn execute(&self, command: &Command) -> Result<()> {
+        let envs = self.envs();
+        debug!("hook envs {envs:?}");
+        command.execute(self.pack, envs)
+    }
+
+    fn envs(&self) -> Vec<(&'static str, String)> {
+        let path = |it: &Path| it.to_string_lossy().into_owned();
+        let decrypted_path = self
+            .config
+            .encrypted
+            .as_ref()
+            .filter(|it| it.enable.is_some_and(identity))
+            .and_then(|it| it.decrypted_path.as_deref());
+        vec![
+            (PACK_ID_ENV, util::hash(&self.pack.to_string_lossy())),
+            (PACK_NAME_ENV, self.pack_name.clone()),
+            (PACK_PATH_ENV, path(self.pack)),
+            (
+                PACK_TARGET_ENV,
+                self.config.target.as_deref().map(path).unwrap_or_default(),
+            ),
+            (
+                PACK_DECRYPTED_PATH_ENV,
+                decrypted_path.map(path).unwrap_or_default(),
+            ),
+            (STOW_CM_OP_ENV, self.op.as_str().to_owned()),
+            // 固定为 0：install / remove / reload / clean 没有 dry-run，脚本只在实际执行时运行；
+            // 保留该变量，以便将来支持 dry-run 时脚本无需修改
+            (STOW_CM_DRY_RUN_ENV, "0".to_owned()),
+            (
+                STOW_CM_LINKS_ENV,
+                self.links_file.as_deref().map(path).unwrap_or_default(),
+            ),
+        ]
+    }
+}
+
+impl Drop for Hooks<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = &self.links_file {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
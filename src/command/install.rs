@@ -1,44 +1,57 @@
+use std::collections::HashSet;
 use std::convert::identity;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{Context, anyhow, bail};
 use log::{debug, info, warn};
+use walkdir::WalkDir;
 
-use crate::config::{Config, EncryptedParams};
+use crate::config::{Config, EncryptedParams, KeyUnavailable, MissingKeyPolicy};
+use crate::constants::DEFAULT_PLACEHOLDER;
 use crate::crypto;
 use crate::error::Result;
 use crate::merge_tree;
 use crate::merge_tree::MergeOption;
+use crate::symlink::Symlink;
 use crate::track_file::Track;
 use crate::util;
 
-use super::{pack_envs, resolve_track_file};
+use super::hook::{Hooks, Op};
+use super::resolve_track_file;
 
 /// install packages
 pub fn install(config: &Arc<Config>, pack: impl AsRef<Path>) -> Result<()> {
-    let pack = Arc::new(pack.as_ref().to_path_buf());
-    let pack_name = config.resolve_pack_name(&pack)?.into_owned();
+    let pack = pack.as_ref();
+    let mut hooks = Hooks::new(config, pack, Op::Install)?;
+    hooks.run("pre_install", config.pre_install.as_ref())?;
+    install_pack(config, pack, &mut hooks)
+}
+
+/// 安装链接并执行 `init` 与 `post_install`，不执行 `pre_install`（由调用方负责）
+pub(super) fn install_pack(config: &Arc<Config>, pack: &Path, hooks: &mut Hooks<'_>) -> Result<()> {
+    let pack = Arc::new(pack.to_path_buf());
     info!("installing");
 
-    install_link(config, &pack)?;
+    let links = install_link(config, &pack)?;
+    hooks.set_links(&links)?;
 
     // execute the init script
-    if let Some(command) = &config.init {
-        info!("running init script");
-        command.execute(&*pack, pack_envs(&pack, &pack_name))?;
-        info!("init script done");
-    }
+    hooks.run_script("init", config.init.as_ref())?;
+    hooks.run("post_install", config.post_install.as_ref())?;
 
     Ok(())
 }
 
-/// install link
-fn install_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
+/// install link, return the created links
+fn install_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<Vec<Symlink>> {
     let pack_name = config.resolve_pack_name(pack.as_ref())?.into_owned();
     let Some(target) = config.target.as_ref() else {
         warn!("target is none, skip install links");
-        return Ok(());
+        return Ok(Vec::new());
     };
 
     // if track file already exists, then the pack has been installed
@@ -97,36 +110,71 @@ fn install_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
         .as_ref()
         .is_some_and(|it| it.enable.is_some_and(identity));
 
+    let mut pending = vec![];
     if decrypted {
         let decrypted_path = decrypted_path
             .ok_or_else(|| anyhow!("{pack_name}: decrypted path is not configured"))?;
 
-        let params = config
+        let encrypted = config
             .encrypted
             .as_ref()
-            .ok_or_else(|| anyhow!("{pack_name}: encrypted config not found"))?
-            .resolve(&pack_name)?;
-        let EncryptedParams {
-            key,
-            left_boundary,
-            right_boundary,
-            encrypted_alg,
-        } = params;
-        let key = key.as_slice();
-
+            .ok_or_else(|| anyhow!("{pack_name}: encrypted config not found"))?;
+        // 密钥不可用时按 `on_missing_key` 跳过含加密内容的文件或以 placeholder 安装
+        let (params, missing_key) = match encrypted.resolve(&pack_name) {
+            Ok(params) => (params, None),
+            Err(e) => match encrypted.on_missing_key.unwrap_or_default() {
+                MissingKeyPolicy::Fail => return Err(e),
+                policy if e.is::<KeyUnavailable>() => {
+                    warn!(
+                        "{e:#}, files with encrypted content are pending (on_missing_key = {})",
+                        format!("{policy:?}").to_lowercase()
+                    );
+                    (encrypted.resolve_without_key(&pack_name)?, Some(policy))
+                }
+                _ => return Err(e),
+            },
+        };
         if !decrypted_path.try_exists()? {
-            std::fs::create_dir_all(decrypted_path).with_context(|| {
-                format!(
-                    "{pack_name}: failed to create decrypted dir, {}",
-                    decrypted_path.display()
-                )
-            })?;
+            util::create_private_dir_all(decrypted_path)
+                .with_context(|| format!("{pack_name}: failed to create decrypted dir"))?;
+        }
+
+        // 仅含加密内容的文件经解密目录安装，其余保持直接链接到 pack
+        let mut routed = Vec::with_capacity(symlinks.len());
+        for symlink in symlinks {
+            routed.extend(unfold_encrypted(symlink, pack, &params)?);
+        }
+        symlinks = routed;
+
+        if missing_key == Some(MissingKeyPolicy::Skip) {
+            let (skipped, linked): (Vec<Symlink>, Vec<Symlink>) = symlinks
+                .into_iter()
+                .partition(|symlink| has_encrypted_content(pack, &symlink.src, &params));
+            for symlink in skipped {
+                info!("skip {}, secrets pending", symlink.src.display());
+                pending.push(symlink.src);
+            }
+            symlinks = linked;
         }
 
         let mut decrypted_file_map = vec![];
         for symlink in &mut symlinks {
-            let decrypted_file_path =
-                util::change_base_path(&symlink.src, pack.as_path(), decrypted_path.as_path())?;
+            if !has_encrypted_content(pack, &symlink.src, &params) {
+                continue;
+            }
+            // 整文件加密的 `.enc` 文件以去掉后缀的文件名安装
+            let decrypted_file_path = crypto::decrypted_file_path(&util::change_base_path(
+                &symlink.src,
+                pack.as_path(),
+                decrypted_path.as_path(),
+            )?);
+            let dst = crypto::decrypted_file_path(&symlink.dst);
+            if dst != symlink.dst {
+                if std::fs::symlink_metadata(&dst).is_ok() {
+                    bail!("check conflict: {}", dst.display());
+                }
+                symlink.dst = dst;
+            }
             debug!(
                 "change_base_path, src={}, base={}, new_base={}, result={}",
                 symlink.src.display(),
@@ -141,42 +189,33 @@ fn install_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
         // decrypted the file
         debug!("decrypted paths {decrypted_file_map:?}");
         for (origin_file_path, decrypted_file_path) in &decrypted_file_map {
-            // 用 symlink_metadata 一次性获取元数据，避免多次 stat() 调用之间的 TOCTOU 竞态窗口
-            match std::fs::symlink_metadata(decrypted_file_path) {
-                Ok(meta) => {
-                    let ft = meta.file_type();
-                    if ft.is_file() || ft.is_symlink() {
-                        std::fs::remove_file(decrypted_file_path)?;
-                    } else if ft.is_dir() {
-                        std::fs::remove_dir_all(decrypted_file_path)?;
-                    }
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    // 目标不存在，无需清理
-                }
-                Err(e) => return Err(e.into()),
+            if missing_key.is_some() {
+                render_placeholder(
+                    &pack_name,
+                    pack,
+                    origin_file_path,
+                    decrypted_file_path,
+                    &params,
+                    encrypted
+                        .placeholder
+                        .as_deref()
+                        .unwrap_or(DEFAULT_PLACEHOLDER),
+                )?;
+                pending.push(origin_file_path.clone());
+            } else {
+                render_decrypted(
+                    &pack_name,
+                    pack,
+                    origin_file_path,
+                    decrypted_file_path,
+                    &params,
+                )?;
             }
-            info!(
-                "decrypt {} to {}",
-                origin_file_path.display(),
-                decrypted_file_path.display()
-            );
-            let content = std::fs::read_to_string(origin_file_path)?;
-            let origin_content = crypto::decrypt_inline(
-                &content,
-                encrypted_alg,
-                key,
-                left_boundary,
-                right_boundary,
-                true,
-            )?;
-            std::fs::write(decrypted_file_path, origin_content).with_context(|| {
-                format!(
-                    "{pack_name}: failed to write decrypted content to path={}",
-                    decrypted_file_path.display()
-                )
-            })?;
         }
+        prune_decrypted(
+            decrypted_path,
+            decrypted_file_map.iter().map(|(_, it)| it.as_path()),
+        )?;
     }
 
     debug!("install paths {symlinks:?}");
@@ -197,11 +236,355 @@ fn install_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
             } else {
                 None
             },
-            links: symlinks,
+            links: symlinks.clone(),
             pack_name: Some(pack_name.clone()),
             pack_path: Some((**pack).clone()),
             target: Some(target.clone()),
+            pending,
         })?,
     )?;
+    Ok(symlinks)
+}
+
+/// 覆写并删除 `decrypted_path` 中不再安装的解密文件（reload 保留了上次的解密目录）。
+/// 仅处理带哈希记录、即由 stow-cm 写入的文件
+fn prune_decrypted<'a>(
+    decrypted_path: &Path,
+    rendered: impl IntoIterator<Item = &'a Path>,
+) -> Result<()> {
+    let rendered: HashSet<&Path> = rendered.into_iter().collect();
+    let stale: Vec<PathBuf> = WalkDir::new(decrypted_path)
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .map(walkdir::DirEntry::into_path)
+        .filter(|path| !rendered.contains(path.as_path()))
+        .filter(|path| util::hash_sibling(path).is_ok_and(|it| it.is_file()))
+        .collect();
+    for path in stale {
+        debug!("remove stale decrypted file {}", path.display());
+        util::shred_file(&path)?;
+        std::fs::remove_file(util::hash_sibling(&path)?)?;
+    }
+    Ok(())
+}
+
+/// 折叠目录下存在需要解密的文件时展开为逐项链接（仅展开通往这些文件的子目录），否则原样保留
+fn unfold_encrypted(
+    symlink: Symlink,
+    pack: &Path,
+    params: &EncryptedParams<'_>,
+) -> Result<Vec<Symlink>> {
+    if !symlink.src.is_dir() {
+        return Ok(vec![symlink]);
+    }
+    let encrypted = WalkDir::new(&symlink.src)
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .any(|entry| has_encrypted_content(pack, entry.path(), params));
+    if !encrypted {
+        return Ok(vec![symlink]);
+    }
+    let mut links = vec![];
+    for entry in std::fs::read_dir(&symlink.src)? {
+        let src = entry?.path();
+        let dst = symlink.dst.join(src.strip_prefix(&symlink.src)?);
+        links.extend(unfold_encrypted(
+            Symlink {
+                src,
+                dst,
+                mode: symlink.mode.clone(),
+            },
+            pack,
+            params,
+        )?);
+    }
+    Ok(links)
+}
+
+/// 文件是否需要解密后安装：整文件加密的文件，或含加密块、明文块（以及转义、secret 引用）的文本文件。
+/// 二进制文件与不含标记的文件直接链接到 pack
+pub(super) fn has_encrypted_content(
+    pack: &Path,
+    path: &Path,
+    params: &EncryptedParams<'_>,
+) -> bool {
+    let rel_path = path.strip_prefix(pack).unwrap_or(path);
+    if params.is_whole_file(rel_path) {
+        return true;
+    }
+    let markers = params.markers(rel_path);
+    std::fs::read_to_string(path)
+        .is_ok_and(|content| content.contains(markers.left) || content.contains(markers.plain_left))
+}
+
+/// 将 pack 中的源文件解密写入 `decrypted_file_path`。install、watch 与 relink-secrets 共用。
+///
+/// 解密文件旁记录源文件哈希，未变化时跳过解密与写入以保留 mtime；
+/// 变化时经同目录临时文件 rename 原子替换，应用不会读到写了一半的文件
+pub(super) fn render_decrypted(
+    pack_name: &str,
+    pack: &Path,
+    origin_file_path: &Path,
+    decrypted_file_path: &Path,
+    params: &EncryptedParams<'_>,
+) -> Result<()> {
+    let source_hash = source_hash(pack, origin_file_path, params)?;
+    let hash_file = util::hash_sibling(decrypted_file_path)?;
+    // 用 symlink_metadata 一次性获取元数据，避免多次 stat() 调用之间的 TOCTOU 竞态窗口
+    match std::fs::symlink_metadata(decrypted_file_path) {
+        Ok(meta) if meta.is_file() => {
+            if std::fs::read_to_string(&hash_file).is_ok_and(|it| it == source_hash) {
+                debug!("unchanged {}", decrypted_file_path.display());
+                return Ok(());
+            }
+        }
+        Ok(meta) if meta.is_dir() => util::shred_dir_all(decrypted_file_path)?,
+        // 符号链接等由 rename 直接替换
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    info!(
+        "decrypt {} to {}",
+        origin_file_path.display(),
+        decrypted_file_path.display()
+    );
+    if let Some(parent) = decrypted_file_path.parent() {
+        util::create_private_dir_all(parent)?;
+    }
+    let origin_content = decrypt_content(pack, origin_file_path, params)?;
+    // 整文件加密多为私钥等敏感文件（如 ssh 拒绝权限过宽的私钥），仅所有者可读写
+    let rel_path = origin_file_path
+        .strip_prefix(pack)
+        .unwrap_or(origin_file_path);
+    let mode = if params.is_whole_file(rel_path) {
+        0o600
+    } else {
+        0o666
+    };
+    write_decrypted(pack_name, decrypted_file_path, &origin_content, mode)?;
+    std::fs::write(&hash_file, source_hash)
+        .with_context(|| format!("failed to write {}", hash_file.display()))?;
     Ok(())
 }
+
+/// 密钥不可用时安装的占位文件：加密块替换为 `stub`，整文件加密的文件内容即为 `stub`。
+/// 不记录源文件哈希，密钥可用后 reload 会重新解密
+fn render_placeholder(
+    pack_name: &str,
+    pack: &Path,
+    origin_file_path: &Path,
+    decrypted_file_path: &Path,
+    params: &EncryptedParams<'_>,
+    stub: &str,
+) -> Result<()> {
+    if decrypted_file_path.is_dir() && !decrypted_file_path.is_symlink() {
+        util::shred_dir_all(decrypted_file_path)?;
+    }
+    let hash_file = util::hash_sibling(decrypted_file_path)?;
+    if hash_file.is_file() {
+        std::fs::remove_file(&hash_file)?;
+    }
+    info!(
+        "placeholder {} to {}",
+        origin_file_path.display(),
+        decrypted_file_path.display()
+    );
+    if let Some(parent) = decrypted_file_path.parent() {
+        util::create_private_dir_all(parent)?;
+    }
+    let rel_path = origin_file_path
+        .strip_prefix(pack)
+        .unwrap_or(origin_file_path);
+    let (content, mode) = if params.is_whole_file(rel_path) {
+        (stub.to_owned(), 0o600)
+    } else {
+        let content = std::fs::read_to_string(origin_file_path)?;
+        (
+            crypto::placeholder_inline(&content, params, rel_path, stub)?,
+            0o666,
+        )
+    };
+    write_decrypted(pack_name, decrypted_file_path, content.as_bytes(), mode)
+}
+
+/// 经同目录临时文件 rename 原子写入解密目录
+fn write_decrypted(pack_name: &str, path: &Path, content: &[u8], mode: u32) -> Result<()> {
+    let temp = util::temp_sibling(path)?;
+    // 上次中断残留的临时文件
+    if std::fs::symlink_metadata(&temp).is_ok() {
+        util::shred_file(&temp)?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp)
+        .and_then(|mut file| file.write_all(content))
+        .and_then(|()| std::fs::rename(&temp, path))
+        .with_context(|| {
+            format!(
+                "{pack_name}: failed to write decrypted content to path={}",
+                path.display()
+            )
+        })
+}
+
+/// 决定解密结果的输入的哈希：源文件内容、是否整文件加密、所用标记，
+/// 以及引用 secret 时的 vault 内容
+fn source_hash(pack: &Path, path: &Path, params: &EncryptedParams<'_>) -> Result<String> {
+    let content = std::fs::read(path)?;
+    let rel_path = path.strip_prefix(pack).unwrap_or(path);
+    let whole = params.is_whole_file(rel_path);
+    let markers = params.markers(rel_path);
+    let vault = match params.vault {
+        Some(vault)
+            if !whole
+                && content
+                    .windows(crypto::SECRET_REF_PREFIX.len())
+                    .any(|it| it == crypto::SECRET_REF_PREFIX.as_bytes()) =>
+        {
+            match std::fs::read(vault) {
+                Ok(vault) => vault,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+                Err(e) => return Err(e.into()),
+            }
+        }
+        _ => vec![],
+    };
+    Ok(util::hash_parts([
+        content.as_slice(),
+        &[u8::from(whole)],
+        markers.left.as_bytes(),
+        markers.right.as_bytes(),
+        markers.plain_left.as_bytes(),
+        markers.plain_right.as_bytes(),
+        vault.as_slice(),
+    ]))
+}
+
+/// 读取并解密 pack 中的源文件：整文件加密的文件按字节解密，其余按内联加密块解密。
+/// 绑定的块以 pack 内相对路径校验
+pub(super) fn decrypt_content(
+    pack: &Path,
+    path: &Path,
+    params: &EncryptedParams<'_>,
+) -> Result<Vec<u8>> {
+    let rel_path = path.strip_prefix(pack).unwrap_or(path);
+    if params.is_whole_file(rel_path) {
+        let content = std::fs::read(path)?;
+        if !crypto::is_encrypted_file(&content) {
+            warn!(
+                "{} is not encrypted yet, run `stow-cm encrypt`",
+                path.display()
+            );
+            return Ok(content);
+        }
+        return crypto::decrypt_file(&String::from_utf8(content)?, params, rel_path)
+            .with_context(|| format!("failed to decrypt {}", path.display()));
+    }
+    let content = std::fs::read_to_string(path)?;
+    Ok(crypto::decrypt_inline(&content, params, rel_path, true)
+        .with_context(|| format!("failed to decrypt {}", path.display()))?
+        .into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::config::EncryptedConfig;
+    use crate::symlink::{Symlink, SymlinkMode};
+    use crate::util::TestDir;
+
+    use super::{has_encrypted_content, unfold_encrypted};
+
+    /// pack/dir 下：plain/a.txt、secret/b.conf（含加密块）、bin.dat（非 UTF-8，含分隔符字节）
+    fn fixture(name: &str) -> (TestDir, PathBuf) {
+        let base = TestDir::new(&format!("install-{name}"));
+        let pack = base.join("pack");
+        let dir = pack.join("dir");
+        std::fs::create_dir_all(dir.join("plain")).unwrap();
+        std::fs::create_dir_all(dir.join("secret")).unwrap();
+        std::fs::write(dir.join("plain/a.txt"), "hello").unwrap();
+        std::fs::write(dir.join("secret/b.conf"), "token = &{v1:x}\n").unwrap();
+        std::fs::write(dir.join("bin.dat"), b"\xff\xfe&{x}").unwrap();
+        (base, pack)
+    }
+
+    fn link(src: &Path, dst: &Path) -> Symlink {
+        Symlink {
+            src: src.to_path_buf(),
+            dst: dst.to_path_buf(),
+            mode: SymlinkMode::Symlink,
+        }
+    }
+
+    #[test]
+    fn has_encrypted_content_test() {
+        let (_base, pack) = fixture("content");
+        std::fs::write(pack.join("key.enc"), b"\x00\x01").unwrap();
+        let encrypted = EncryptedConfig::default();
+        let params = encrypted.resolve_without_key("content").unwrap();
+
+        assert!(has_encrypted_content(
+            &pack,
+            &pack.join("dir/secret/b.conf"),
+            &params
+        ));
+        assert!(has_encrypted_content(&pack, &pack.join("key.enc"), &params));
+        assert!(!has_encrypted_content(
+            &pack,
+            &pack.join("dir/plain/a.txt"),
+            &params
+        ));
+        assert!(!has_encrypted_content(
+            &pack,
+            &pack.join("dir/bin.dat"),
+            &params
+        ));
+    }
+
+    #[test]
+    fn unfold_only_path_to_encrypted() {
+        let (base, pack) = fixture("unfold");
+        let target = base.join("target");
+        let encrypted = EncryptedConfig::default();
+        let params = encrypted.resolve_without_key("unfold").unwrap();
+
+        let links =
+            unfold_encrypted(link(&pack.join("dir"), &target.join("dir")), &pack, &params).unwrap();
+        let mut links: Vec<_> = links
+            .into_iter()
+            .map(|it| {
+                (
+                    it.src.strip_prefix(&pack).unwrap().to_path_buf(),
+                    it.dst.strip_prefix(&target).unwrap().to_path_buf(),
+                )
+            })
+            .collect();
+        links.sort();
+        // plain 保持折叠，二进制文件直接链接，只有 secret 展开到文件
+        assert_eq!(
+            links,
+            [
+                ("dir/bin.dat", "dir/bin.dat"),
+                ("dir/plain", "dir/plain"),
+                ("dir/secret/b.conf", "dir/secret/b.conf"),
+            ]
+            .map(|(src, dst)| (PathBuf::from(src), PathBuf::from(dst)))
+        );
+
+        // 不含加密内容的目录原样保留
+        let links = unfold_encrypted(
+            link(&pack.join("dir/plain"), &target.join("dir/plain")),
+            &pack,
+            &params,
+        )
+        .unwrap();
+        let links: Vec<_> = links.into_iter().map(|it| (it.src, it.dst)).collect();
+        assert_eq!(links, [(pack.join("dir/plain"), target.join("dir/plain"))]);
+    }
+}
@@ -0,0 +1,437 @@
+use std::cell::Cell;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, bail};
+use log::{debug, info, warn};
+use regex::RegexSet;
+
+use crate::base64;
+use crate::config::{self, Config, EncryptedParams};
+use crate::crypto;
+use crate::error::Result;
+use crate::util;
+use crate::vault;
+
+use super::crypto::text_files;
+use super::installed_pack_paths;
+
+/// 生成 `alg` 对应长度的随机密钥，以 base64 写入 `key_path`（权限 0600）。
+/// `force` 为 `false` 时拒绝覆盖已存在的文件。
+pub fn key_gen(key_path: &Path, alg: &str, force: bool) -> Result<()> {
+    let key = crypto::generate_key(alg)?;
+
+    if let Some(parent) = key_path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).mode(0o600);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options
+        .open(key_path)
+        .with_context(|| format!("failed to create key file {}", key_path.display()))?;
+    writeln!(file, "{}", base64::encode(&key))?;
+
+    info!("generated {alg} key at {}", key_path.display());
+    Ok(())
+}
+
+/// 待轮换的单个文件：新内容在全部计算成功后才落盘
+pub(super) struct Rotated {
+    pub(super) path: PathBuf,
+    pub(super) content: String,
+}
+
+/// 用新密钥重新加密 pack 中的所有加密块。
+///
+/// 传入 `paths` 时轮换指定 pack；否则轮换所有已安装且 `key` 或 `keyring` 中含有 `old_key` 的 pack，
+/// 跳过无法解析密钥或使用 X25519 的 pack。
+/// 所有文件先在内存中完成解密与重新加密，任一失败则不写入任何文件；
+/// 随后按 `replace_all` 替换，中途失败会还原已替换的文件。
+/// 完成后提示各 pack 更新仍指向旧密钥的 `key_path` / `key_cmd` / `key_env`。
+pub fn key_rotate(
+    global: &Config,
+    paths: Vec<PathBuf>,
+    new_key_path: &Path,
+    old_key_path: Option<&Path>,
+) -> Result<()> {
+    let new_key = read_key(new_key_path)?;
+    let old_key = old_key_path.map(read_key).transpose()?;
+    let new_key_path = util::shell_expand_full(new_key_path)?;
+
+    // 未指定 pack 时，从已安装 pack 中筛选使用旧密钥的 pack
+    let select_by_key = paths.is_empty();
+    let packs = if select_by_key {
+        if old_key.is_none() {
+            bail!("either PACK_PATH or --old-key is required");
+        }
+        installed_pack_paths()?
+    } else {
+        util::canonicalize(paths)?
+    };
+
+    let mut rotated: Vec<Rotated> = Vec::new();
+    let mut rotated_packs: Vec<String> = Vec::new();
+    let mut block_count = 0_usize;
+    for pack in &packs {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        util::scoped_log_prefix(&pack_name, || -> Result<()> {
+            let Some(encrypted) = config
+                .encrypted
+                .as_ref()
+                .filter(|it| it.enable.is_some_and(std::convert::identity))
+            else {
+                if !select_by_key {
+                    warn!("pack is not enable encrypted");
+                }
+                return Ok(());
+            };
+            // 按旧密钥选择 pack 时，无法解析密钥或使用 X25519 的 pack 只是与本次轮换无关
+            let params = match encrypted.resolve(&pack_name) {
+                Ok(params) => params,
+                Err(e) if select_by_key => {
+                    debug!("failed to resolve key, skip: {e:#}");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if params.encrypted_alg == crypto::X25519_ALG {
+                if select_by_key {
+                    debug!("pack uses X25519, skip");
+                    return Ok(());
+                }
+                bail!(
+                    "{pack_name}: X25519 packs have no shared key to rotate, \
+                     update recipients and run `stow-cm key rewrap` instead"
+                );
+            }
+            if select_by_key && !params.keys().any(|key| old_key.as_deref() == Some(key)) {
+                debug!("pack does not use the old key, skip");
+                return Ok(());
+            }
+            let key = old_key.as_deref().unwrap_or(&params.key);
+            let key_id = crypto::key_id(key);
+            if new_key.len() != crypto::key_len(params.encrypted_alg)? {
+                bail!(
+                    "{pack_name}: new key length does not match {}",
+                    params.encrypted_alg
+                );
+            }
+
+            let ignore_re = config.ignore_regex()?;
+            let before = block_count;
+            for path in sealed_files(pack, ignore_re.as_ref(), &params) {
+                // 多个 pack 共享的 vault 只处理一次
+                if rotated.iter().any(|it| it.path == path) {
+                    continue;
+                }
+                let Ok(content) = std::fs::read_to_string(&path) else {
+                    continue;
+                };
+                let rel_path = block_path(pack, &path, &params);
+                let (processed, count) = map_blocks(&content, &params, rel_path, |block, aad| {
+                    // 使用 keyring 中其他密钥加密的块保持不变
+                    if crypto::block_key_id(block).is_some_and(|id| id != key_id) {
+                        return Ok(None);
+                    }
+                    let plain = crypto::decrypt_bytes(block, params.encrypted_alg, key, aad)
+                        .with_context(|| format!("path={}", path.display()))?;
+                    // 保持块原有的绑定状态
+                    let aad = crypto::is_bound_block(block).then_some(aad);
+                    crypto::encrypt_bytes(&plain, params.encrypted_alg, &new_key, aad).map(Some)
+                })?;
+                if count > 0 {
+                    info!("rotate {count} block(s) in {}", path.display());
+                    block_count += count;
+                    rotated.push(Rotated {
+                        path,
+                        content: processed,
+                    });
+                }
+            }
+            if block_count > before {
+                rotated_packs.push(pack_name.clone());
+            }
+            Ok(())
+        })?;
+    }
+
+    if rotated.is_empty() {
+        warn!("no encrypted blocks found, nothing to rotate");
+        return Ok(());
+    }
+
+    replace_all(&rotated)?;
+
+    info!(
+        "rotated {block_count} block(s) in {} file(s)",
+        rotated.len()
+    );
+    // 配置中的密钥来源不会被修改，不更新则下次安装无法解密
+    for pack_name in &rotated_packs {
+        warn!(
+            "{pack_name}: update key_path / key_cmd / key_env to the new key {}, \
+             the old key can no longer decrypt its blocks",
+            new_key_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// 输出 X25519 identity 文件对应的 base64 公钥，用于配置其他机器的 `recipients`
+pub fn key_pub(key_path: &Path) -> Result<()> {
+    let identity = read_key(key_path)?;
+    println!("{}", base64::encode(&crypto::x25519_public_key(&identity)?));
+    Ok(())
+}
+
+/// 将 X25519 pack 中每个块的内容密钥重新包裹给当前 `recipients`，密文本身不变。
+///
+/// 不传 `paths` 则处理所有已安装的 X25519 pack；与 `key_rotate` 相同，全部计算成功后才落盘。
+pub fn key_rewrap(global: &Config, paths: Vec<PathBuf>) -> Result<()> {
+    let select_installed = paths.is_empty();
+    let packs = if select_installed {
+        installed_pack_paths()?
+    } else {
+        util::canonicalize(paths)?
+    };
+
+    let mut rewrapped: Vec<Rotated> = Vec::new();
+    let mut block_count = 0_usize;
+    for pack in &packs {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        util::scoped_log_prefix(&pack_name, || -> Result<()> {
+            let Some(encrypted) = config
+                .encrypted
+                .as_ref()
+                .filter(|it| it.enable.is_some_and(std::convert::identity))
+            else {
+                if !select_installed {
+                    warn!("pack is not enable encrypted");
+                }
+                return Ok(());
+            };
+            let params = encrypted.resolve(&pack_name)?;
+            if params.encrypted_alg != crypto::X25519_ALG {
+                if !select_installed {
+                    warn!("pack does not use X25519, skip");
+                }
+                return Ok(());
+            }
+
+            let ignore_re = config.ignore_regex()?;
+            for path in sealed_files(pack, ignore_re.as_ref(), &params) {
+                if rewrapped.iter().any(|it| it.path == path) {
+                    continue;
+                }
+                let Ok(content) = std::fs::read_to_string(&path) else {
+                    continue;
+                };
+                let rel_path = block_path(pack, &path, &params);
+                let (processed, count) = map_blocks(&content, &params, rel_path, |block, _| {
+                    crypto::rewrap(block, &params.key, &params.recipients)
+                        .with_context(|| format!("path={}", path.display()))
+                        .map(Some)
+                })?;
+                if count > 0 {
+                    info!("rewrap {count} block(s) in {}", path.display());
+                    block_count += count;
+                    rewrapped.push(Rotated {
+                        path,
+                        content: processed,
+                    });
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    if rewrapped.is_empty() {
+        warn!("no X25519 blocks found, nothing to rewrap");
+        return Ok(());
+    }
+    replace_all(&rewrapped)?;
+
+    info!(
+        "rewrapped {block_count} block(s) in {} file(s)",
+        rewrapped.len()
+    );
+    Ok(())
+}
+
+/// pack 中可能含加密块的文件：文本文件，以及存在时的 vault（可能位于 pack 之外）
+pub(super) fn sealed_files(
+    pack: &Path,
+    ignore_re: Option<&RegexSet>,
+    params: &EncryptedParams<'_>,
+) -> Vec<PathBuf> {
+    let mut files = text_files(pack, ignore_re);
+    if let Some(vault) = params.vault.filter(|it| it.is_file())
+        && !files.iter().any(|it| it == vault)
+    {
+        files.push(vault.to_path_buf());
+    }
+    files
+}
+
+/// 块绑定的文件路径：pack 内相对路径，vault 使用固定路径
+pub(super) fn block_path<'a>(
+    pack: &Path,
+    path: &'a Path,
+    params: &EncryptedParams<'_>,
+) -> &'a Path {
+    vault::rel_path(path, params).unwrap_or_else(|| path.strip_prefix(pack).unwrap_or(path))
+}
+
+/// 对文件中的每个加密块执行 `convert`，整文件加密的文件视为单个块；
+/// `convert` 额外接收块绑定的 associated data，返回 `None` 的块保持不变。返回新内容与处理的块数
+pub(super) fn map_blocks(
+    content: &str,
+    params: &EncryptedParams<'_>,
+    rel_path: &Path,
+    convert: impl Fn(&str, &[u8]) -> Result<Option<String>>,
+) -> Result<(String, usize)> {
+    let count = Cell::new(0_usize);
+    let convert = |block: &str, label: &str| -> Result<String> {
+        // vault 引用不是密文
+        if crypto::secret_ref(block).is_some() {
+            return Ok(block.to_owned());
+        }
+        match convert(block, &crypto::site_aad(rel_path, label, params))? {
+            Some(converted) => {
+                count.set(count.get() + 1);
+                Ok(converted)
+            }
+            None => Ok(block.to_owned()),
+        }
+    };
+    let processed = if crypto::is_encrypted_file(content.as_bytes()) {
+        crypto::map_file_block(content, |block| convert(block, ""))?
+    } else {
+        let markers = params.markers(rel_path);
+        util::var_inplace_labeled(content, markers.left, markers.right, false, convert)?
+            .into_owned()
+    };
+    Ok((processed, count.get()))
+}
+
+/// 原子地替换一组文件：先全部写入临时文件，任一失败则清理已写入的临时文件；
+/// 随后为每个原文件建立硬链接备份再 rename 替换，中途失败时用备份还原已替换的文件并清理剩余临时文件，
+/// 全部成功后删除备份
+pub(super) fn replace_all(files: &[Rotated]) -> Result<()> {
+    let mut staged = Vec::with_capacity(files.len());
+    for file in files {
+        match util::stage_write(&file.path, &file.content) {
+            Ok(temp) => staged.push((temp, file.path.as_path())),
+            Err(e) => {
+                for (temp, _) in &staged {
+                    let _ = std::fs::remove_file(temp);
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let mut replaced: Vec<(PathBuf, &Path)> = Vec::with_capacity(staged.len());
+    let mut result = Ok(());
+    for (temp, path) in &staged {
+        match replace_with_backup(temp, path) {
+            Ok(backup) => replaced.push((backup, path)),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    if let Err(e) = result {
+        for (backup, path) in replaced.iter().rev() {
+            if let Err(restore_err) = std::fs::rename(backup, path) {
+                warn!(
+                    "failed to restore {} from {}: {restore_err}",
+                    path.display(),
+                    backup.display()
+                );
+            }
+        }
+        for (temp, _) in &staged {
+            let _ = std::fs::remove_file(temp);
+        }
+        return Err(e);
+    }
+    for (backup, _) in &replaced {
+        let _ = std::fs::remove_file(backup);
+    }
+    Ok(())
+}
+
+/// 将原文件硬链接为备份后用 `temp` 替换，返回备份路径
+fn replace_with_backup(temp: &Path, path: &Path) -> Result<PathBuf> {
+    let backup = util::backup_sibling(path)?;
+    // 上次中断残留的备份
+    if std::fs::symlink_metadata(&backup).is_ok() {
+        std::fs::remove_file(&backup)?;
+    }
+    std::fs::hard_link(path, &backup)
+        .with_context(|| format!("failed to back up {}", path.display()))?;
+    if let Err(e) = std::fs::rename(temp, path) {
+        let _ = std::fs::remove_file(&backup);
+        return Err(e).with_context(|| format!("failed to replace {}", path.display()));
+    }
+    Ok(backup)
+}
+
+fn read_key(key_path: &Path) -> Result<Vec<u8>> {
+    config::read_key_file("key", &util::shell_expand_full(key_path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::util::TestDir;
+
+    use super::{Rotated, replace_all};
+
+    #[test]
+    fn replace_all_rolls_back() {
+        let dir = TestDir::new("key-replace");
+        let a = dir.join("a");
+        let b = dir.join("b");
+        std::fs::write(&a, "old a").unwrap();
+        std::fs::write(&b, "old b").unwrap();
+        let rotated = |paths: [&PathBuf; 2]| {
+            paths.map(|path| Rotated {
+                path: path.clone(),
+                content: "new".to_owned(),
+            })
+        };
+
+        // 目录无法建立硬链接备份：已替换的 a 被还原，不留下临时文件和备份
+        let c = dir.join("c");
+        std::fs::create_dir(&c).unwrap();
+        assert!(replace_all(&rotated([&a, &c])).is_err());
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "old a");
+        let mut names: Vec<_> = std::fs::read_dir(&*dir)
+            .unwrap()
+            .map(|it| it.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c"]);
+
+        replace_all(&rotated([&a, &b])).unwrap();
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(&b).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 3);
+    }
+}
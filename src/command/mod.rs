@@ -1,20 +1,39 @@
 mod adopt;
+mod audit;
 mod clean;
 mod crypto;
+mod diff;
+mod edit;
+mod filter;
+mod hook;
 mod init;
 mod install;
+mod key;
 mod list;
+mod relink;
 mod remove;
+mod reseal;
+mod secret;
 mod status;
+mod watch;
 
 pub use adopt::adopt;
+pub use audit::audit;
 pub use clean::clean;
-pub use crypto::{decrypt, encrypt};
+pub use crypto::{CryptoOptions, decrypt, encrypt, encrypt_check, file_packs, verify};
+pub use diff::diff;
+pub use edit::{cat, edit};
+pub use filter::{filter_clean, filter_smudge, filter_textconv, git_setup};
 pub use init::init;
 pub use install::install;
+pub use key::{key_gen, key_pub, key_rewrap, key_rotate};
 pub use list::list;
+pub use relink::relink_secrets;
 pub use remove::remove;
+pub use reseal::reseal;
+pub use secret::{secret_get, secret_list, secret_rm, secret_set};
 pub use status::status;
+pub use watch::watch;
 
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -29,14 +48,7 @@ use crate::paths::{pack_track_file, stow_cm_state_dir};
 use crate::track_file::Track;
 use crate::util;
 
-/// 构造 pack 操作的环境变量 `[(PACK_ID_ENV, hash), (PACK_NAME_ENV, pack_name)]`，
-/// 消除 `install`/`clean`/`remove` 中的重复注入逻辑。
-pub(super) fn pack_envs(pack: &Path, pack_name: &str) -> [(&'static str, String); 2] {
-    [
-        (PACK_ID_ENV, util::hash(&pack.to_string_lossy())),
-        (PACK_NAME_ENV, pack_name.to_owned()),
-    ]
-}
+use hook::{Hooks, Op};
 
 /// 解析 pack 对应的 track file 路径，消除 `install`/`clean`/`remove` 中的重复逻辑。
 pub(super) fn resolve_track_file(pack: &Path, pack_name: &str) -> Result<PathBuf> {
@@ -49,6 +61,30 @@ pub(super) fn resolve_track_file(pack: &Path, pack_name: &str) -> Result<PathBuf
     Ok(track_file)
 }
 
+/// 扫描 `$XDG_STATE_HOME/stow-cm/` 下所有 track file，返回已安装 pack 的原始路径。
+/// 无法解析或未记录 `pack_path` 的 track file 会被跳过。
+pub(super) fn installed_pack_paths() -> Result<Vec<PathBuf>> {
+    let state_dir = stow_cm_state_dir();
+    if !state_dir.try_exists()? {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(&state_dir)? {
+        let track_path = entry?.path().join(TRACK_FILE_NAME);
+        let Ok(content) = std::fs::read_to_string(&track_path) else {
+            continue;
+        };
+        let Ok(track) = toml::from_str::<Track>(&content) else {
+            continue;
+        };
+        if let Some(pack_path) = track.pack_path {
+            paths.push(pack_path);
+        }
+    }
+    Ok(paths)
+}
+
 /// 根据 `PACK_ID`（支持前缀匹配）查找对应的 pack 路径。
 ///
 /// 扫描 `$XDG_STATE_HOME/stow-cm/` 下所有 track file，
@@ -137,9 +173,15 @@ pub fn resolve_pack_ids(ids: &[String]) -> Result<Vec<PathBuf>> {
     Ok(results)
 }
 
-/// reload packages
+/// reload packages, the decrypted dir is kept so unchanged files are not rewritten
 pub fn reload(config: &Arc<Config>, pack: impl AsRef<Path>) -> Result<()> {
-    remove::remove(config, &pack)?;
-    install::install(config, &pack)?;
+    let pack = pack.as_ref();
+    let mut hooks = Hooks::new(config, pack, Op::Reload)?;
+    // pre 钩子在任何改动之前执行，失败时 pack 保持原样
+    hooks.run("pre_remove", config.pre_remove.as_ref())?;
+    hooks.run("pre_install", config.pre_install.as_ref())?;
+    remove::remove_keep_decrypted(config, pack, &mut hooks)?;
+    install::install_pack(config, pack, &mut hooks)?;
+    hooks.run("on_reload", config.on_reload.as_ref())?;
     Ok(())
 }
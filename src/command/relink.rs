@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::track_file::Track;
+use crate::util;
+
+use super::install::render_decrypted;
+use super::{installed_pack_paths, resolve_track_file};
+
+/// 重新生成缺失的解密文件并恢复指向它们的链接，如重启后 `$XDG_RUNTIME_DIR`（tmpfs）被清空。
+///
+/// 不传 `paths` 则处理所有已安装 pack；仍存在的解密文件保持不变。
+pub fn relink_secrets(global: &Config, paths: Vec<PathBuf>) -> Result<()> {
+    let select_installed = paths.is_empty();
+    let packs = if select_installed {
+        installed_pack_paths()?
+    } else {
+        util::canonicalize(paths)?
+    };
+
+    let mut file_count = 0_usize;
+    for pack in &packs {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        util::scoped_log_prefix(&pack_name, || -> Result<()> {
+            let track_file = resolve_track_file(pack, &pack_name)?;
+            if !track_file.try_exists()? {
+                if !select_installed {
+                    warn!("pack is not installed");
+                }
+                return Ok(());
+            }
+            let track: Track = toml::from_str(&std::fs::read_to_string(&track_file)?)?;
+            let Some(decrypted) = &track.decrypted_path else {
+                return Ok(());
+            };
+
+            let missing: Vec<_> = track
+                .links
+                .iter()
+                .filter(|link| link.src.starts_with(decrypted))
+                .filter(|link| std::fs::symlink_metadata(&link.src).is_err())
+                .collect();
+            if missing.is_empty() {
+                return Ok(());
+            }
+
+            let params = config
+                .encrypted
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("{pack_name}: encrypted config not found"))?
+                .resolve(&pack_name)?;
+            util::create_private_dir_all(decrypted)?;
+            for link in missing {
+                render_decrypted(
+                    &pack_name,
+                    pack,
+                    &track.pack_source(link),
+                    &link.src,
+                    &params,
+                )?;
+                if std::fs::symlink_metadata(&link.dst).is_err() {
+                    info!("symlink {link}");
+                    link.create(true)?;
+                }
+                file_count += 1;
+            }
+            Ok(())
+        })?;
+    }
+
+    info!("relinked {file_count} decrypted file(s)");
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::convert::identity;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -5,37 +6,63 @@ use log::{debug, info, warn};
 
 use crate::config::Config;
 use crate::error::Result;
+use crate::symlink::Symlink;
 use crate::track_file::Track;
+use crate::util;
 
-use super::{pack_envs, resolve_track_file};
+use super::hook::{Hooks, Op};
+use super::resolve_track_file;
 
 /// remove packages
 pub fn remove<P: AsRef<Path>>(config: &Arc<Config>, pack: P) -> Result<()> {
-    let pack = Arc::new(pack.as_ref().to_path_buf());
-    let pack_name = config.resolve_pack_name(&pack)?.into_owned();
+    let pack = pack.as_ref();
+    let mut hooks = Hooks::new(config, pack, Op::Remove)?;
+    hooks.run("pre_remove", config.pre_remove.as_ref())?;
+    remove_pack(config, pack, true, &mut hooks)
+}
+
+/// 移除 pack 但保留解密目录，供 reload 重新安装时跳过未变化的文件；
+/// 不执行 `pre_remove`（由 reload 在任何改动之前执行）
+pub(super) fn remove_keep_decrypted(
+    config: &Arc<Config>,
+    pack: &Path,
+    hooks: &mut Hooks<'_>,
+) -> Result<()> {
+    remove_pack(config, pack, false, hooks)
+}
+
+fn remove_pack(
+    config: &Arc<Config>,
+    pack: &Path,
+    shred_decrypted: bool,
+    hooks: &mut Hooks<'_>,
+) -> Result<()> {
+    let pack = Arc::new(pack.to_path_buf());
     info!("removing");
 
-    remove_link(config, &pack)?;
+    let links = remove_link(config, &pack, shred_decrypted)?;
+    hooks.set_links(&links)?;
 
     // execute the clear script
-    if let Some(command) = &config.clear {
-        info!("running clear script");
-        command.execute(&*pack, pack_envs(&pack, &pack_name))?;
-        info!("clear script done");
-    }
+    hooks.run_script("clear", config.clear.as_ref())?;
+    hooks.run("post_remove", config.post_remove.as_ref())?;
 
     Ok(())
 }
 
-/// remove links
-fn remove_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
+/// remove links, return the removed links
+fn remove_link(
+    config: &Arc<Config>,
+    pack: &Arc<PathBuf>,
+    shred_decrypted: bool,
+) -> Result<Vec<Symlink>> {
     let pack_name = config.resolve_pack_name(pack.as_ref())?.into_owned();
 
     let track_file = resolve_track_file(pack, &pack_name)?;
 
     if !track_file.try_exists()? {
         warn!("no links installed");
-        return Ok(());
+        return Ok(Vec::new());
     }
 
     let track: Track = toml::from_str(std::fs::read_to_string(track_file.as_path())?.as_str())?;
@@ -49,15 +76,23 @@ fn remove_link(config: &Arc<Config>, pack: &Arc<PathBuf>) -> Result<()> {
     }
 
     // obtain the decryption path from the track file
-    // if is decrypted, delete the decrypted file
+    // if is decrypted, overwrite and delete the decrypted files
+    // reload 时仅在解密目录仍被当前配置使用时保留
     if let Some(path) = track.decrypted_path
+        && (shred_decrypted || !is_decrypted_dir(config, &path))
         && path.try_exists()?
     {
         debug!("remove decrypted dir, {}", path.display());
-        std::fs::remove_dir_all(path)?;
+        util::shred_dir_all(path)?;
     }
 
     std::fs::remove_file(track_file)?;
 
-    Ok(())
+    Ok(symlinks)
+}
+
+fn is_decrypted_dir(config: &Config, path: &Path) -> bool {
+    config.encrypted.as_ref().is_some_and(|it| {
+        it.enable.is_some_and(identity) && it.decrypted_path.as_deref() == Some(path)
+    })
 }
@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use log::{info, warn};
+
+use crate::config::Config;
+use crate::crypto;
+use crate::error::Result;
+use crate::util;
+
+use super::installed_pack_paths;
+use super::key::{Rotated, block_path, map_blocks, replace_all, sealed_files};
+
+/// 将 pack 中尚未绑定的加密块（旧格式、v1、未绑定的 X25519 块）用当前配置重新加密，
+/// 绑定到所在文件的 pack 内相对路径与块标签。
+///
+/// 需开启 `bind_blocks`；不传 `paths` 则处理所有已安装 pack。全部计算成功后才落盘。
+pub fn reseal(global: &Config, paths: Vec<PathBuf>) -> Result<()> {
+    let select_installed = paths.is_empty();
+    let packs = if select_installed {
+        installed_pack_paths()?
+    } else {
+        util::canonicalize(paths)?
+    };
+
+    let mut resealed: Vec<Rotated> = Vec::new();
+    let mut block_count = 0_usize;
+    for pack in &packs {
+        let config = Config::for_pack(pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(pack)?.into_owned();
+        util::scoped_log_prefix(&pack_name, || -> Result<()> {
+            let Some(encrypted) = config
+                .encrypted
+                .as_ref()
+                .filter(|it| it.enable.is_some_and(std::convert::identity))
+            else {
+                if !select_installed {
+                    warn!("pack is not enable encrypted");
+                }
+                return Ok(());
+            };
+            let mut params = encrypted.resolve(&pack_name)?;
+            // reseal 正是为了处理未绑定的块，不受 `bind_blocks = "strict"` 限制
+            params.reject_unbound = false;
+            if !params.bind_blocks {
+                warn!("bind_blocks is not enabled, skip reseal");
+                return Ok(());
+            }
+
+            let ignore_re = config.ignore_regex()?;
+            for path in sealed_files(pack, ignore_re.as_ref(), &params) {
+                if resealed.iter().any(|it| it.path == path) {
+                    continue;
+                }
+                let Ok(content) = std::fs::read_to_string(&path) else {
+                    continue;
+                };
+                let rel_path = block_path(pack, &path, &params);
+                let (processed, count) = map_blocks(&content, &params, rel_path, |block, aad| {
+                    if crypto::is_bound_block(block) {
+                        return Ok(None);
+                    }
+                    let plain = crypto::decrypt_block_bytes(block, &params, aad)
+                        .with_context(|| format!("path={}", path.display()))?;
+                    crypto::encrypt_block_bytes(&plain, &params, aad).map(Some)
+                })?;
+                if count > 0 {
+                    info!("reseal {count} block(s) in {}", path.display());
+                    block_count += count;
+                    resealed.push(Rotated {
+                        path,
+                        content: processed,
+                    });
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    if resealed.is_empty() {
+        info!("no unbound blocks found, nothing to reseal");
+        return Ok(());
+    }
+    replace_all(&resealed)?;
+
+    info!(
+        "resealed {block_count} block(s) in {} file(s)",
+        resealed.len()
+    );
+    Ok(())
+}
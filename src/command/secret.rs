@@ -0,0 +1,93 @@
+use std::io::{IsTerminal, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use log::info;
+
+use crate::config::{Config, EncryptedParams};
+use crate::crypto;
+use crate::error::Result;
+use crate::vault;
+
+/// 加载启用加密的 pack，以其 vault 路径与加密参数调用 `f`
+fn open_vault<R>(
+    global: &Config,
+    pack: &Path,
+    f: impl FnOnce(&Path, &EncryptedParams<'_>) -> Result<R>,
+) -> Result<R> {
+    let pack = std::fs::canonicalize(pack)?;
+    let config = Config::for_pack(&pack, global, None, false)?;
+    let pack_name = config.resolve_pack_name(&pack)?.into_owned();
+    let encrypted = config
+        .encrypted
+        .as_ref()
+        .filter(|it| it.enable.is_some_and(std::convert::identity))
+        .ok_or_else(|| anyhow!("{pack_name}: encryption is not enabled"))?;
+    let params = encrypted.resolve(&pack_name)?;
+    let vault_path = params
+        .vault
+        .ok_or_else(|| anyhow!("{pack_name}: vault is not configured"))?;
+    f(vault_path, &params)
+}
+
+/// 设置 secret：终端中交互输入（需确认），否则从 stdin 读取并去掉末尾换行
+pub fn secret_set(global: &Config, pack: &Path, name: &str) -> Result<()> {
+    if !crypto::is_secret_name(name) {
+        bail!("invalid secret name {name:?}, use ASCII letters, digits, `_`, `-` and `.`");
+    }
+    open_vault(global, pack, |vault_path, params| {
+        let mut secrets = vault::load(vault_path, params)?;
+        let value = read_secret(name)?;
+        secrets.insert(name.to_owned(), value);
+        vault::save(vault_path, &secrets, params)?;
+        info!("set secret {name} in {}", vault_path.display());
+        Ok(())
+    })
+}
+
+/// 输出 secret 的值
+pub fn secret_get(global: &Config, pack: &Path, name: &str) -> Result<()> {
+    open_vault(global, pack, |vault_path, params| {
+        let secrets = vault::load(vault_path, params)?;
+        let value = secrets
+            .get(name)
+            .ok_or_else(|| anyhow!("secret {name} not found in {}", vault_path.display()))?;
+        println!("{value}");
+        Ok(())
+    })
+}
+
+/// 列出 vault 中的 secret 名称，不输出值
+pub fn secret_list(global: &Config, pack: &Path) -> Result<()> {
+    open_vault(global, pack, |vault_path, params| {
+        for name in vault::load(vault_path, params)?.keys() {
+            println!("{name}");
+        }
+        Ok(())
+    })
+}
+
+/// 删除 secret
+pub fn secret_rm(global: &Config, pack: &Path, name: &str) -> Result<()> {
+    open_vault(global, pack, |vault_path, params| {
+        let mut secrets = vault::load(vault_path, params)?;
+        if secrets.remove(name).is_none() {
+            bail!("secret {name} not found in {}", vault_path.display());
+        }
+        vault::save(vault_path, &secrets, params)?;
+        info!("removed secret {name} from {}", vault_path.display());
+        Ok(())
+    })
+}
+
+fn read_secret(name: &str) -> Result<String> {
+    if std::io::stdin().is_terminal() {
+        return inquire::Password::new(&format!("{name}:"))
+            .prompt()
+            .map_err(|e| anyhow!("{e}"));
+    }
+    let mut value = String::new();
+    std::io::stdin().read_to_string(&mut value)?;
+    let trimmed = value.strip_suffix('\n').unwrap_or(&value);
+    Ok(trimmed.strip_suffix('\r').unwrap_or(trimmed).to_owned())
+}
@@ -7,19 +7,24 @@ use serde::Serialize;
 use crate::command::resolve_track_file;
 use crate::config::Config;
 use crate::constants::TRACK_FILE_NAME;
+use crate::crypto;
 use crate::error::Result;
 use crate::paths::stow_cm_state_dir;
 use crate::symlink::{Symlink, SymlinkMode};
 use crate::track_file::Track;
 use crate::util;
 
-/// 链接状态枚举，按严重程度升序排列（OK < MISSING/DANGLING < OVERWRITTEN/DRIFT）
+/// 链接状态枚举，按严重程度升序排列（OK < MISSING/DANGLING/WIPED/PENDING < OVERWRITTEN/DRIFT）
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum LinkStatus {
     Ok,
     Missing,
     Dangling,
+    /// 链接指向的解密文件已不存在（如重启清空了 `$XDG_RUNTIME_DIR`），pack 源文件仍在
+    Wiped,
+    /// 安装时密钥不可用，文件被跳过或以 placeholder 安装，密钥可用后 `reload` 补齐
+    Pending,
     Overwritten,
     Drift,
 }
@@ -30,6 +35,8 @@ impl LinkStatus {
             LinkStatus::Ok => "OK",
             LinkStatus::Missing => "MI",
             LinkStatus::Dangling => "DA",
+            LinkStatus::Wiped => "WI",
+            LinkStatus::Pending => "PE",
             LinkStatus::Overwritten => "OW",
             LinkStatus::Drift => "DR",
         }
@@ -82,6 +89,15 @@ fn check_symlink(link: &Symlink) -> LinkStatus {
     }
 }
 
+/// 解密文件丢失而 pack 源文件仍在，可通过 `relink-secrets` 恢复
+fn is_wiped(track: &Track, link: &Symlink) -> bool {
+    track
+        .decrypted_path
+        .as_ref()
+        .is_some_and(|decrypted| link.src.starts_with(decrypted))
+        && track.pack_source(link).exists()
+}
+
 /// 尝试修复缺失的链接
 fn fix_missing(link: &Symlink) -> Result<()> {
     info!(
@@ -97,7 +113,13 @@ fn check_pack_links(pack_name: &str, track: &Track, fix: bool) -> Vec<LinkEntry>
     let mut entries = Vec::new();
 
     for link in &track.links {
-        let status = check_symlink(link);
+        let mut status = check_symlink(link);
+        if status == LinkStatus::Dangling && is_wiped(track, link) {
+            status = LinkStatus::Wiped;
+        }
+        if status == LinkStatus::Ok && track.pending.contains(&track.pack_source(link)) {
+            status = LinkStatus::Pending;
+        }
         let mut fixed = false;
 
         if status == LinkStatus::Missing && fix {
@@ -118,9 +140,38 @@ fn check_pack_links(pack_name: &str, track: &Track, fix: bool) -> Vec<LinkEntry>
         });
     }
 
+    // on_missing_key = "skip" 时未链接的文件
+    for source in &track.pending {
+        if track
+            .links
+            .iter()
+            .any(|link| track.pack_source(link) == *source)
+        {
+            continue;
+        }
+        entries.push(LinkEntry {
+            pack: pack_name.to_string(),
+            status: LinkStatus::Pending,
+            src: source.display().to_string(),
+            dst: pending_dst(track, source)
+                .map(|dst| dst.display().to_string())
+                .unwrap_or_default(),
+            mode: String::new(),
+            fixed: false,
+        });
+    }
+
     entries
 }
 
+/// 被跳过的 pack 源文件安装后的位置
+fn pending_dst(track: &Track, source: &Path) -> Option<PathBuf> {
+    let rel = source.strip_prefix(track.pack_path.as_ref()?).ok()?;
+    Some(crypto::decrypted_file_path(
+        &track.target.as_ref()?.join(rel),
+    ))
+}
+
 /// 从 track.toml 路径解析 pack 名称和 Track 记录
 fn read_track_from_path(track_path: &Path) -> Option<(String, Track)> {
     let content = std::fs::read_to_string(track_path).ok()?;
@@ -233,6 +284,8 @@ fn output_entries(entries: &[LinkEntry], json: bool) {
     let mut dangling_total = 0usize;
     let mut overwritten_total = 0usize;
     let mut drift_total = 0usize;
+    let mut wiped_total = 0usize;
+    let mut pending_total = 0usize;
 
     for (pack_name, pack_entries) in &by_pack {
         let counts = count_statuses(pack_entries);
@@ -241,17 +294,32 @@ fn output_entries(entries: &[LinkEntry], json: bool) {
         dangling_total += counts.2;
         overwritten_total += counts.3;
         drift_total += counts.4;
-
-        let has_issues = counts.1 > 0 || counts.2 > 0 || counts.3 > 0 || counts.4 > 0;
+        wiped_total += counts.5;
+        pending_total += counts.6;
+
+        let has_issues = counts.1 > 0
+            || counts.2 > 0
+            || counts.3 > 0
+            || counts.4 > 0
+            || counts.5 > 0
+            || counts.6 > 0;
         let status_icon = if has_issues { "\u{26a0} " } else { "\u{2713} " };
         println!(
-            "\n{status_icon}{pack_name}: {ok} OK, {mi} MISSING, {da} DANGLING, {ow} OVERWRITTEN, {dr} DRIFT",
+            "\n{status_icon}{pack_name}: {ok} OK, {mi} MISSING, {da} DANGLING, {wi} WIPED, {pe} PENDING, {ow} OVERWRITTEN, {dr} DRIFT",
             ok = counts.0,
             mi = counts.1,
             da = counts.2,
+            wi = counts.5,
+            pe = counts.6,
             ow = counts.3,
             dr = counts.4,
         );
+        if counts.5 > 0 {
+            println!("  decrypted files are gone, run `stow-cm relink-secrets`");
+        }
+        if counts.6 > 0 {
+            println!("  secrets pending, run `stow-cm reload` once the key is available");
+        }
 
         for e in pack_entries {
             if e.status != LinkStatus::Ok {
@@ -266,15 +334,22 @@ fn output_entries(entries: &[LinkEntry], json: bool) {
         }
     }
 
-    let total = ok_total + missing_total + dangling_total + overwritten_total + drift_total;
+    let total = ok_total
+        + missing_total
+        + dangling_total
+        + wiped_total
+        + pending_total
+        + overwritten_total
+        + drift_total;
     println!(
         "\nTotal: {total} links ({ok_total} OK, {missing_total} MISSING, {dangling_total} DANGLING, \
-         {overwritten_total} OVERWRITTEN, {drift_total} DRIFT)"
+         {wiped_total} WIPED, {pending_total} PENDING, {overwritten_total} OVERWRITTEN, {drift_total} DRIFT)"
     );
 }
 
-fn count_statuses(entries: &[&LinkEntry]) -> (usize, usize, usize, usize, usize) {
-    let (mut ok, mut missing, mut dangling, mut overwritten, mut drift) = (0, 0, 0, 0, 0);
+fn count_statuses(entries: &[&LinkEntry]) -> (usize, usize, usize, usize, usize, usize, usize) {
+    let (mut ok, mut missing, mut dangling, mut overwritten, mut drift, mut wiped, mut pending) =
+        (0, 0, 0, 0, 0, 0, 0);
     for e in entries {
         match e.status {
             LinkStatus::Ok => ok += 1,
@@ -282,9 +357,11 @@ fn count_statuses(entries: &[&LinkEntry]) -> (usize, usize, usize, usize, usize)
             LinkStatus::Dangling => dangling += 1,
             LinkStatus::Overwritten => overwritten += 1,
             LinkStatus::Drift => drift += 1,
+            LinkStatus::Wiped => wiped += 1,
+            LinkStatus::Pending => pending += 1,
         }
     }
-    (ok, missing, dangling, overwritten, drift)
+    (ok, missing, dangling, overwritten, drift, wiped, pending)
 }
 
 /// 检查已安装 pack 的状态一致性。
@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use std::convert::identity;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, anyhow};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use lazy_regex::{Lazy, Regex, lazy_regex};
+use log::{debug, info, warn};
+use regex::RegexSet;
+use walkdir::WalkDir;
+
+use crate::config::{Config, EncryptedParams};
+use crate::constants::CONFIG_FILE_NAME;
+use crate::crypto;
+use crate::error::Result;
+use crate::symlink::{Symlink, SymlinkMode};
+use crate::track_file::Track;
+use crate::util;
+
+use super::install::{has_encrypted_content, render_decrypted};
+use super::{installed_pack_paths, resolve_track_file};
+
+/// inotify 监听的事件集合：新建/删除/移动/写入完成
+const WATCH_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::CLOSE_WRITE);
+
+/// 编辑器保存时产生的临时 / 备份文件：vim 的 `4913`、`.swp` / `.swx` 与 `~` 后缀，emacs 的 `#name#` 与 `.#name`
+#[allow(clippy::non_std_lazy_statics)]
+static SCRATCH_FILE: Lazy<Regex> = lazy_regex!(r"^(?:4913|.*~|\.#.*|#.*#|.*\.sw[a-px])$");
+
+/// 单个被监听 pack 的运行时状态
+struct WatchedPack {
+    pack: PathBuf,
+    pack_name: String,
+    config: Config,
+    target: PathBuf,
+    ignore_re: Option<RegexSet>,
+    track_file: PathBuf,
+    track: Track,
+}
+
+/// 监听已安装 pack 的源目录，增量同步链接与解密文件。
+///
+/// 不传 `paths` 则监听 `state_dir` 下所有已安装 pack；
+/// 新增文件写入完成后自动链接，删除文件移除对应链接，写入完成后重新解密 / 复制派生文件。
+/// pack 配置或 vault 变化时重新加载配置并重新渲染所有派生文件；编辑器的临时文件不处理。
+pub fn watch(global: &Config, paths: Vec<PathBuf>) -> Result<()> {
+    let paths = if paths.is_empty() {
+        installed_pack_paths()?
+    } else {
+        util::canonicalize(paths)?
+    };
+
+    let mut inotify = Inotify::init().context("failed to initialize inotify")?;
+    let mut watches: HashMap<WatchDescriptor, (usize, PathBuf)> = HashMap::new();
+    // 配置与 vault 所在目录，vault 可能位于 pack 之外
+    let mut dep_watches: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    let mut packs: Vec<WatchedPack> = Vec::new();
+
+    for pack in paths {
+        let config = Config::for_pack(&pack, global, None, false)?;
+        let pack_name = config.resolve_pack_name(&pack)?.into_owned();
+        let track_file = resolve_track_file(&pack, &pack_name)?;
+        let Some(target) = config.target.clone() else {
+            util::scoped_log_prefix(&pack_name, || warn!("target is none, skip watch"));
+            continue;
+        };
+        if !track_file.try_exists()? {
+            util::scoped_log_prefix(&pack_name, || warn!("pack is not installed, skip watch"));
+            continue;
+        }
+        let track: Track = toml::from_str(&std::fs::read_to_string(&track_file)?)?;
+        let ignore_re = config.ignore_regex()?;
+
+        let index = packs.len();
+        add_watches(&mut inotify, &mut watches, index, &pack, ignore_re.as_ref())?;
+        let state = WatchedPack {
+            pack,
+            pack_name,
+            config,
+            target,
+            ignore_re,
+            track_file,
+            track,
+        };
+        add_dep_watches(&mut inotify, &mut dep_watches, &state)?;
+        packs.push(state);
+    }
+
+    if packs.is_empty() {
+        warn!("no installed packs to watch");
+        return Ok(());
+    }
+    info!("watching {} pack(s), press Ctrl-C to stop", packs.len());
+
+    let mut buffer = [0_u8; 4096];
+    loop {
+        let events: Vec<(WatchDescriptor, EventMask, Option<OsString>)> = inotify
+            .read_events_blocking(&mut buffer)?
+            .map(|event| (event.wd, event.mask, event.name.map(ToOwned::to_owned)))
+            .collect();
+
+        for (wd, mask, name) in events {
+            if mask.contains(EventMask::Q_OVERFLOW) {
+                warn!("inotify queue overflow, some changes may be missed; run `stow-cm reload`");
+                continue;
+            }
+            if mask.contains(EventMask::IGNORED) {
+                watches.remove(&wd);
+                dep_watches.remove(&wd);
+                continue;
+            }
+            let Some(name) = name else {
+                continue;
+            };
+
+            if let Some(dir) = dep_watches.get(&wd)
+                && mask.intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO)
+            {
+                let path = dir.join(&name);
+                for state in packs
+                    .iter_mut()
+                    .filter(|it| it.dependencies().contains(&path))
+                {
+                    let pack_name = state.pack_name.clone();
+                    let result = util::scoped_log_prefix(&pack_name, || -> Result<()> {
+                        info!("{} changed, render again", path.display());
+                        state.refresh(global)?;
+                        add_dep_watches(&mut inotify, &mut dep_watches, state)
+                    });
+                    if let Err(e) = result {
+                        util::scoped_log_prefix(&pack_name, || {
+                            warn!("failed to refresh after {} changed: {e:#}", path.display());
+                        });
+                    }
+                }
+            }
+
+            let Some((index, dir)) = watches.get(&wd).cloned() else {
+                continue;
+            };
+            let Some(state) = packs.get_mut(index) else {
+                continue;
+            };
+            let path = dir.join(name);
+            if state.is_ignored(&path) || is_scratch(&path) {
+                continue;
+            }
+            debug!("event {mask:?} on {}", path.display());
+
+            let pack_name = state.pack_name.clone();
+            let result = util::scoped_log_prefix(&pack_name, || -> Result<()> {
+                if mask.contains(EventMask::ISDIR)
+                    && mask.intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                {
+                    add_watches(
+                        &mut inotify,
+                        &mut watches,
+                        index,
+                        &path,
+                        state.ignore_re.as_ref(),
+                    )?;
+                    state.link_path(&path)
+                } else if mask.intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO)
+                    // 新建的普通文件等写入完成（CLOSE_WRITE）后再链接，符号链接没有写入事件
+                    || (mask.contains(EventMask::CREATE) && path.is_symlink())
+                {
+                    state.link_path(&path)
+                } else if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                    state.unlink_path(&path)
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = result {
+                util::scoped_log_prefix(&pack_name, || {
+                    warn!("failed to sync {}: {e:#}", path.display());
+                });
+            }
+        }
+    }
+}
+
+/// 递归为 `dir` 及其所有未被忽略的子目录注册 inotify 监听
+fn add_watches(
+    inotify: &mut Inotify,
+    watches: &mut HashMap<WatchDescriptor, (usize, PathBuf)>,
+    index: usize,
+    dir: &Path,
+    ignore_re: Option<&RegexSet>,
+) -> Result<()> {
+    let dirs = WalkDir::new(dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            !ignore_re.is_some_and(|re| re.is_match(&entry.path().to_string_lossy()))
+        })
+        .filter_map(std::result::Result::ok)
+        .filter(|entry| entry.file_type().is_dir());
+    for entry in dirs {
+        let wd = inotify
+            .watches()
+            .add(entry.path(), WATCH_MASK)
+            .with_context(|| format!("failed to watch {}", entry.path().display()))?;
+        watches.insert(wd, (index, entry.into_path()));
+    }
+    Ok(())
+}
+
+/// 为 pack 的配置文件与 vault 所在目录注册监听。监听目录而非文件，编辑器以 rename 替换文件时也能收到事件
+fn add_dep_watches(
+    inotify: &mut Inotify,
+    dep_watches: &mut HashMap<WatchDescriptor, PathBuf>,
+    state: &WatchedPack,
+) -> Result<()> {
+    for dep in state.dependencies() {
+        let Some(dir) = dep.parent().filter(|it| it.is_dir()) else {
+            continue;
+        };
+        let wd = inotify
+            .watches()
+            .add(dir, WATCH_MASK)
+            .with_context(|| format!("failed to watch {}", dir.display()))?;
+        dep_watches.insert(wd, dir.to_path_buf());
+    }
+    Ok(())
+}
+
+fn is_scratch(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| SCRATCH_FILE.is_match(name))
+}
+
+impl WatchedPack {
+    fn is_ignored(&self, path: &Path) -> bool {
+        self.ignore_re
+            .as_ref()
+            .is_some_and(|re| re.is_match(&path.to_string_lossy()))
+    }
+
+    fn decrypted_path(&self) -> Option<&PathBuf> {
+        let enabled = self
+            .config
+            .encrypted
+            .as_ref()
+            .is_some_and(|it| it.enable.is_some_and(identity));
+        if enabled {
+            self.track.decrypted_path.as_ref()
+        } else {
+            None
+        }
+    }
+
+    fn source_of(&self, link: &Symlink) -> PathBuf {
+        self.track.pack_source(link)
+    }
+
+    fn params(&self) -> Result<EncryptedParams<'_>> {
+        self.config
+            .encrypted
+            .as_ref()
+            .ok_or_else(|| anyhow!("{}: encrypted config not found", self.pack_name))?
+            .resolve(&self.pack_name)
+    }
+
+    /// 源文件已被经解密目录安装的链接（文件或折叠目录）覆盖
+    fn is_decrypted_link(&self, path: &Path) -> bool {
+        self.decrypted_path().is_some_and(|decrypted| {
+            self.track.links.iter().any(|link| {
+                link.src.starts_with(decrypted) && path.starts_with(self.source_of(link))
+            })
+        })
+    }
+
+    /// 源文件是否经解密目录安装：已被解密链接覆盖，或文件含加密内容
+    fn is_rendered(&self, path: &Path) -> Result<bool> {
+        if self.decrypted_path().is_none() {
+            return Ok(false);
+        }
+        Ok(
+            self.is_decrypted_link(path)
+                || has_encrypted_content(&self.pack, path, &self.params()?),
+        )
+    }
+
+    /// 将 pack 内源文件解密到 `decrypted_path` 下的对应位置，返回解密文件路径；
+    /// 不经解密目录安装的文件不渲染
+    fn render(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let Some(decrypted) = self.decrypted_path() else {
+            return Ok(None);
+        };
+        if !self.is_rendered(path)? {
+            return Ok(None);
+        }
+        let params = self.params()?;
+        let decrypted_file =
+            crypto::decrypted_file_path(&util::change_base_path(path, &self.pack, decrypted)?);
+        render_decrypted(&self.pack_name, &self.pack, path, &decrypted_file, &params)?;
+        Ok(Some(decrypted_file))
+    }
+
+    fn save_track(&self) -> Result<()> {
+        let temp = util::stage_write(&self.track_file, toml::to_string_pretty(&self.track)?)?;
+        std::fs::rename(&temp, &self.track_file)
+            .with_context(|| format!("failed to write {}", self.track_file.display()))?;
+        Ok(())
+    }
+
+    /// 影响渲染结果的 pack 外部输入：pack 配置文件与 vault
+    fn dependencies(&self) -> Vec<PathBuf> {
+        let mut deps = vec![self.pack.join(CONFIG_FILE_NAME)];
+        if let Some(vault) = self
+            .config
+            .encrypted
+            .as_ref()
+            .and_then(|it| it.vault.clone())
+        {
+            deps.push(vault);
+        }
+        deps
+    }
+
+    /// 配置或 vault 变化：重新加载配置，重新渲染所有解密文件并刷新副本。
+    /// 源文件哈希包含 vault 与分隔符，未受影响的文件跳过
+    fn refresh(&mut self, global: &Config) -> Result<()> {
+        self.config = Config::for_pack(&self.pack, global, Some(&self.pack_name), false)?;
+        self.ignore_re = self.config.ignore_regex()?;
+
+        let decrypted = self.decrypted_path();
+        let mut sources: Vec<PathBuf> = Vec::new();
+        for link in &self.track.links {
+            let derived = link.mode == SymlinkMode::Copy
+                || decrypted.is_some_and(|decrypted| link.src.starts_with(decrypted));
+            if !derived {
+                continue;
+            }
+            let source = self.source_of(link);
+            if source.is_dir() {
+                sources.extend(
+                    WalkDir::new(&source)
+                        .into_iter()
+                        .filter_map(std::result::Result::ok)
+                        .filter(|entry| entry.file_type().is_file())
+                        .map(walkdir::DirEntry::into_path)
+                        .filter(|file| !self.is_ignored(file) && !is_scratch(file)),
+                );
+            } else {
+                sources.push(source);
+            }
+        }
+        for source in sources {
+            if let Err(e) = self.rerender(&source) {
+                warn!("failed to render {}: {e:#}", source.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// 新增文件或目录：已链接的重新渲染，被折叠目录覆盖的仅渲染解密文件，其余新建链接
+    fn link_path(&mut self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            let files: Vec<PathBuf> = WalkDir::new(path)
+                .into_iter()
+                .filter_map(std::result::Result::ok)
+                .filter(|entry| entry.file_type().is_file())
+                .map(walkdir::DirEntry::into_path)
+                .filter(|file| !self.is_ignored(file) && !is_scratch(file))
+                .collect();
+            for file in files {
+                self.link_path(&file)?;
+            }
+            return Ok(());
+        }
+
+        if self
+            .track
+            .links
+            .iter()
+            .any(|link| self.source_of(link) == path)
+        {
+            return self.rerender(path);
+        }
+
+        let folded = self.track.links.iter().any(|link| {
+            link.mode == SymlinkMode::Symlink && path.starts_with(self.source_of(link))
+        });
+        if folded {
+            if self.is_decrypted_link(path) {
+                if let Some(decrypted_file) = self.render(path)? {
+                    info!("render {}", decrypted_file.display());
+                }
+            } else if self.is_rendered(path)? {
+                // 折叠目录直接链接到 pack，新增的加密文件需重新安装以展开目录
+                warn!(
+                    "{} has encrypted content under a folded dir, run `stow-cm reload`",
+                    path.display()
+                );
+            }
+            return Ok(());
+        }
+
+        let rendered = self.is_rendered(path)?;
+        let mut dst = util::change_base_path(path, &self.pack, &self.target)?;
+        if rendered {
+            dst = crypto::decrypted_file_path(&dst);
+        }
+        if std::fs::symlink_metadata(&dst).is_ok() {
+            warn!("conflict, skip link: {}", dst.display());
+            return Ok(());
+        }
+        let src = self.render(path)?.unwrap_or_else(|| path.to_path_buf());
+        let symlink = Symlink {
+            src,
+            dst,
+            mode: self.config.symlink_mode.clone().unwrap_or_default(),
+        };
+        info!("symlink {symlink}");
+        symlink.create(false)?;
+        self.track.links.push(symlink);
+        self.save_track()
+    }
+
+    /// 删除文件或目录：移除所有源路径位于其下的链接及解密文件
+    fn unlink_path(&mut self, path: &Path) -> Result<()> {
+        let (removed, kept): (Vec<Symlink>, Vec<Symlink>) = std::mem::take(&mut self.track.links)
+            .into_iter()
+            .partition(|link| self.source_of(link).starts_with(path));
+        self.track.links = kept;
+
+        for symlink in &removed {
+            info!("remove symlink {symlink}");
+            symlink.remove()?;
+        }
+
+        if let Some(decrypted) = self.decrypted_path() {
+            let decrypted_file =
+                crypto::decrypted_file_path(&util::change_base_path(path, &self.pack, decrypted)?);
+            match std::fs::symlink_metadata(&decrypted_file) {
+                Ok(meta) if meta.is_dir() => util::shred_dir_all(&decrypted_file)?,
+                Ok(meta) if meta.is_file() => {
+                    util::shred_file(&decrypted_file)?;
+                    let hash_file = util::hash_sibling(&decrypted_file)?;
+                    if hash_file.is_file() {
+                        std::fs::remove_file(hash_file)?;
+                    }
+                }
+                Ok(_) => std::fs::remove_file(&decrypted_file)?,
+                Err(_) => {}
+            }
+        }
+
+        if removed.is_empty() {
+            Ok(())
+        } else {
+            self.save_track()
+        }
+    }
+
+    /// 源文件写入完成：重新解密并刷新 copy 模式的副本
+    fn rerender(&self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+        let linked = self
+            .track
+            .links
+            .iter()
+            .find(|link| self.source_of(link) == path);
+        let covered = linked.is_some()
+            || self.track.links.iter().any(|link| {
+                link.mode == SymlinkMode::Symlink && path.starts_with(self.source_of(link))
+            });
+        if !covered {
+            return Ok(());
+        }
+
+        if let Some(decrypted_file) = self.render(path)? {
+            info!("render {}", decrypted_file.display());
+        }
+        if let Some(link) = linked
+            && link.mode == SymlinkMode::Copy
+        {
+            info!("copy {link}");
+            link.create(true)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    /// 未加密、尚无链接的 pack：`base/pack` 安装到 `base/target`
+    fn watched(name: &str, mode: SymlinkMode) -> (TestDir, WatchedPack) {
+        let base = TestDir::new(&format!("watch-{name}"));
+        let pack = base.join("pack");
+        let target = base.join("target");
+        std::fs::create_dir_all(&pack).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+        let state = WatchedPack {
+            pack: pack.clone(),
+            pack_name: name.to_owned(),
+            config: Config {
+                symlink_mode: Some(mode),
+                encrypted: None,
+                ..Config::default()
+            },
+            target,
+            ignore_re: None,
+            track_file: base.join("track.toml"),
+            track: Track {
+                links: Vec::new(),
+                decrypted_path: None,
+                pack_name: Some(name.to_owned()),
+                pack_path: Some(pack),
+                target: None,
+                pending: Vec::new(),
+            },
+        };
+        (base, state)
+    }
+
+    #[test]
+    fn link_and_unlink_test() {
+        let (_base, mut state) = watched("link", SymlinkMode::Symlink);
+        let file = state.pack.join("dir/a");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, "a").unwrap();
+
+        // 新建目录：其中的文件逐个链接并写入 track file
+        state.link_path(&state.pack.join("dir")).unwrap();
+        let dst = state.target.join("dir/a");
+        assert_eq!(std::fs::read_link(&dst).unwrap(), file);
+        assert_eq!(state.track.links.len(), 1);
+        let saved: Track =
+            toml::from_str(&std::fs::read_to_string(&state.track_file).unwrap()).unwrap();
+        assert_eq!(saved.links.len(), 1);
+
+        // 已链接的文件再次出现只重新渲染，不重复链接
+        state.link_path(&file).unwrap();
+        assert_eq!(state.track.links.len(), 1);
+
+        // 删除目录：移除其下所有链接
+        state.unlink_path(&state.pack.join("dir")).unwrap();
+        assert!(std::fs::symlink_metadata(&dst).is_err());
+        assert!(state.track.links.is_empty());
+    }
+
+    #[test]
+    fn folded_dir_not_relinked() {
+        let (_base, mut state) = watched("folded", SymlinkMode::Symlink);
+        let dir = state.pack.join("dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        let folded = Symlink {
+            src: dir.clone(),
+            dst: state.target.join("dir"),
+            mode: SymlinkMode::Symlink,
+        };
+        folded.create(false).unwrap();
+        state.track.links.push(folded);
+
+        // 折叠目录下的新文件已经可见，不新建链接
+        std::fs::write(dir.join("b"), "b").unwrap();
+        state.link_path(&dir.join("b")).unwrap();
+        assert_eq!(state.track.links.len(), 1);
+        assert!(!state.track_file.exists());
+    }
+
+    #[test]
+    fn rerender_refreshes_copy() {
+        let (_base, mut state) = watched("copy", SymlinkMode::Copy);
+        let file = state.pack.join("c");
+        std::fs::write(&file, "old").unwrap();
+        state.link_path(&file).unwrap();
+        let dst = state.target.join("c");
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "old");
+
+        // 写入完成后刷新副本；未安装的文件不处理
+        std::fs::write(&file, "new").unwrap();
+        state.rerender(&file).unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "new");
+        let other = state.pack.join("other");
+        std::fs::write(&other, "x").unwrap();
+        state.rerender(&other).unwrap();
+        assert!(!state.target.join("other").exists());
+    }
+
+    #[test]
+    fn scratch_names() {
+        for name in [
+            "4913",
+            "a.conf~",
+            ".a.conf.swp",
+            ".a.swx",
+            "#a.conf#",
+            ".#a.conf",
+        ] {
+            assert!(is_scratch(Path::new(name)), "{name}");
+        }
+        for name in ["a.conf", "swp", ".swprc", "4913.conf", "a#b"] {
+            assert!(!is_scratch(Path::new(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn refresh_renders_dependants() {
+        let (_base, mut state) = watched("refresh", SymlinkMode::Copy);
+        std::fs::write(state.pack.join(CONFIG_FILE_NAME), "").unwrap();
+        let file = state.pack.join("c");
+        std::fs::write(&file, "old").unwrap();
+        state.link_path(&file).unwrap();
+        let dst = state.target.join("c");
+
+        // 未收到源文件事件，配置变化后仍刷新所有副本
+        std::fs::write(&file, "new").unwrap();
+        assert!(
+            state
+                .dependencies()
+                .contains(&state.pack.join(CONFIG_FILE_NAME))
+        );
+        state.refresh(&Config::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "new");
+        assert!(!util::temp_sibling(&state.track_file).unwrap().exists());
+    }
+}
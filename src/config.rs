@@ -1,12 +1,17 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs;
-use std::io::Write;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, anyhow, bail};
+use log::{debug, info, warn};
 use maplit::hashmap;
 use merge::option::with_recurse_strategy;
 use regex::RegexSet;
@@ -16,16 +21,21 @@ use stow_cm_macros::Finalize;
 
 use crate::base64;
 use crate::constants::{
-    CONFIG_FILE_NAME, DEFAULT_CRYPT_ALG, DEFAULT_DECRYPT_LEFT_BOUNDARY,
-    DEFAULT_DECRYPT_RIGHT_BOUNDARY, PACK_ID_ENV, PACK_NAME_ENV,
+    BACKUP_FILE_SUFFIX, CONFIG_FILE_NAME, DEFAULT_CRYPT_ALG, DEFAULT_DECRYPT_LEFT_BOUNDARY,
+    DEFAULT_DECRYPT_RIGHT_BOUNDARY, DEFAULT_KDF_ITERATIONS, DEFAULT_PLACEHOLDER,
+    DEFAULT_PLAIN_LEFT_BOUNDARY, DEFAULT_PLAIN_RIGHT_BOUNDARY, HASH_FILE_SUFFIX, PACK_ID_ENV,
+    PACK_NAME_ENV, TEMP_FILE_SUFFIX, UNSET_VALUE, VAULT_FILE_NAME,
 };
+use crate::crypto;
 use crate::error::Result;
-use crate::merge::{Finalize, Merge, SystemInstance};
+use crate::merge::{Finalize, Merge, SystemInstance, UnsetMarker};
 use crate::paths::{
     default_pack_decrypt, default_pack_target, global_config_path, global_xdg_config_path,
+    runtime_pack_decrypt,
 };
 use crate::symlink::SymlinkMode;
 use crate::util;
+use crate::vault::{self, Secrets};
 
 /// pack config
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Merge, Finalize)]
@@ -64,7 +74,33 @@ pub struct Config {
     #[finalize(skip)]
     pub clear: Option<Command>,
 
-    /// encrypted config
+    /// hooks run before install, a failure aborts the install
+    #[serde(default, deserialize_with = "deserialize_hooks")]
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub pre_install: Option<Vec<Hook>>,
+
+    /// hooks run after install (after `init`)
+    #[serde(default, deserialize_with = "deserialize_hooks")]
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub post_install: Option<Vec<Hook>>,
+
+    /// hooks run before remove / clean, a failure aborts the removal
+    #[serde(default, deserialize_with = "deserialize_hooks")]
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub pre_remove: Option<Vec<Hook>>,
+
+    /// hooks run after remove / clean (after `clear`)
+    #[serde(default, deserialize_with = "deserialize_hooks")]
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub post_remove: Option<Vec<Hook>>,
+
+    /// hooks run after reload
+    #[serde(default, deserialize_with = "deserialize_hooks")]
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub on_reload: Option<Vec<Hook>>,
+
+    /// encrypted config, merged field by field: the pack only overrides the fields it sets
+    #[merge(strategy = merge::option::recurse)]
     pub encrypted: Option<EncryptedConfig>,
 }
 
@@ -77,29 +113,313 @@ pub struct EncryptedConfig {
     pub enable: Option<bool>,
     /// decrypted file path when install, default path is ${`XDG_STATE_HOME`:-~/.local/state}/stow-cm/${pack_name}/decrypted/
     pub decrypted_path: Option<PathBuf>,
+    /// put decrypted files under `$XDG_RUNTIME_DIR` (tmpfs) instead of `decrypted_path`, default to false
+    #[finalize(skip)]
+    pub runtime_dir: Option<bool>,
     /// left boundary of content to be decrypted
     #[serde(alias = "left_boundry")]
     pub left_boundary: Option<String>,
     /// right boundary of content to be decrypted
     #[serde(alias = "right_boundry")]
     pub right_boundary: Option<String>,
+    /// left boundary of plaintext to be encrypted
+    pub plain_left_boundary: Option<String>,
+    /// right boundary of plaintext to be encrypted
+    pub plain_right_boundary: Option<String>,
     /// the algorithm of encrypted content, default to chacha20poly1305
     pub encrypted_alg: Option<String>,
-    /// the algorithm of encrypted content, default to chacha20poly1305
+    /// path of the base64 encoded key file
     pub key_path: Option<PathBuf>,
+    /// shell command printing the base64 encoded key (e.g. `pass show dotfiles/key`),
+    /// run at most once per invocation
+    pub key_cmd: Option<String>,
+    /// env var holding the base64 encoded key
+    pub key_env: Option<String>,
+    /// derive the key from a passphrase when `key_path` is not configured or not exist
+    pub passphrase: Option<PassphraseConfig>,
+    /// additional key files used only for decryption, blocks pick their key by key id
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub keyring: Option<Vec<PathBuf>>,
+    /// base64 encoded X25519 public keys, used when `encrypted_alg` is X25519;
+    /// the public key of the identity in `key_path` is always included
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub recipients: Option<Vec<String>>,
+    /// regexes matched against the pack-relative path, matched files are encrypted as one blob
+    /// (files with `.enc` suffix always are)
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub files: Option<Vec<String>>,
+    /// bind new blocks to their pack-relative path and label (AEAD associated data), default to false;
+    /// `"strict"` also refuses to decrypt unbound blocks
+    #[finalize(skip)]
+    pub bind_blocks: Option<BindBlocks>,
+    /// secret vault referenced by `&{secret:name}` blocks, relative to the pack;
+    /// default to the nearest `stow-cm.vault` in the pack dir or its parent dirs
+    pub vault: Option<PathBuf>,
+    /// per-file boundaries, the first rule matching the pack-relative path wins
+    #[merge(strategy = with_recurse_strategy(merge::vec::append))]
+    pub rules: Option<Vec<Rule>>,
+    /// what install does when the key is unavailable, default to fail
+    #[finalize(skip)]
+    pub on_missing_key: Option<MissingKeyPolicy>,
+    /// stub installed in place of each encrypted block with `on_missing_key = "placeholder"`
+    pub placeholder: Option<String>,
+}
+
+/// 密钥不可用（CI、容器、尚未取得密钥的新机器）时 install 的处理方式
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingKeyPolicy {
+    /// 安装失败
+    #[default]
+    Fail,
+    /// 跳过含加密内容的文件，其余照常链接
+    Skip,
+    /// 含加密内容的文件以 `placeholder` 替换加密块后安装
+    Placeholder,
+}
+
+/// 本机没有密钥的错误：`key_path` 或 `keyring` 文件不存在、`key_env` 未设置、
+/// 非终端中需要口令且未设置 `passphrase.env`。install 据此按 `on_missing_key` 处理，
+/// 密钥损坏、`key_cmd` 失败等其他错误照常失败
+#[derive(Debug)]
+pub struct KeyUnavailable(String);
+
+impl KeyUnavailable {
+    fn error(message: String) -> anyhow::Error {
+        anyhow::Error::new(KeyUnavailable(message))
+    }
+}
+
+impl std::fmt::Display for KeyUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for KeyUnavailable {}
+
+/// 匹配 `files` 的文件使用的分隔符，未设置的字段沿用 pack 级配置；
+/// `plain_right_boundary` 未设置时优先沿用规则的 `right_boundary`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MarkerRule {
+    /// regexes matched against the pack-relative path
+    pub files: Vec<String>,
+    pub left_boundary: Option<String>,
+    pub right_boundary: Option<String>,
+    pub plain_left_boundary: Option<String>,
+    pub plain_right_boundary: Option<String>,
+}
+
+/// `bind_blocks` 的取值：`true` / `false`，或 `"strict"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BindBlocks {
+    /// 新块不绑定路径
+    #[default]
+    Off,
+    /// 新块绑定路径，仍可解密未绑定的旧块
+    On,
+    /// 新块绑定路径，拒绝解密未绑定的块（`reseal` 除外）
+    Strict,
+}
+
+impl Serialize for BindBlocks {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Off => serializer.serialize_bool(false),
+            Self::On => serializer.serialize_bool(true),
+            Self::Strict => serializer.serialize_str("strict"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindBlocks {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Bool(bool),
+            Str(String),
+        }
+        match Value::deserialize(deserializer)? {
+            Value::Bool(false) => Ok(Self::Off),
+            Value::Bool(true) => Ok(Self::On),
+            Value::Str(value) if value == "strict" => Ok(Self::Strict),
+            Value::Str(value) => Err(serde::de::Error::custom(format!(
+                "expected true, false or \"strict\", found \"{value}\""
+            ))),
+        }
+    }
+}
+
+/// `rules` 中的一项：分隔符规则，或 `"!"`（丢弃其后合并自全局配置的规则）
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Rule {
+    Unset(Unset),
+    Markers(MarkerRule),
+}
+
+impl Rule {
+    pub fn markers(&self) -> Option<&MarkerRule> {
+        match self {
+            Self::Unset(_) => None,
+            Self::Markers(rule) => Some(rule),
+        }
+    }
+}
+
+impl UnsetMarker for Rule {
+    fn is_unset(&self) -> bool {
+        matches!(self, Self::Unset(_))
+    }
+}
+
+/// 配置中的 `"!"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unset;
+
+impl Serialize for Unset {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(UNSET_VALUE)
+    }
+}
+
+impl<'de> Deserialize<'de> for Unset {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        if value.trim() == UNSET_VALUE {
+            Ok(Unset)
+        } else {
+            Err(serde::de::Error::custom(format!(
+                "expected \"{UNSET_VALUE}\", found \"{value}\""
+            )))
+        }
+    }
+}
+
+/// passphrase key derivation config (PBKDF2-HMAC-SHA256)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Merge, Finalize)]
+#[merge(strategy = merge::option::overwrite_none)]
+pub struct PassphraseConfig {
+    /// base64 encoded salt
+    pub salt: Option<String>,
+    /// PBKDF2 iterations, default to 600000
+    #[finalize(skip)]
+    pub iterations: Option<u32>,
+    /// read the passphrase from this env var instead of prompting
+    pub env: Option<String>,
+}
+
+/// 一个文件中加密块（`left...right`）与明文块（`plain_left...plain_right`）的分隔符
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Markers<'a> {
+    pub left: &'a str,
+    pub right: &'a str,
+    pub plain_left: &'a str,
+    pub plain_right: &'a str,
+}
+
+/// pack 级分隔符与按文件匹配的 `rules`
+#[derive(Debug, Clone)]
+pub struct MarkerSet<'a> {
+    pub default: Markers<'a>,
+    pub rules: Vec<(RegexSet, Markers<'a>)>,
+}
+
+impl<'a> MarkerSet<'a> {
+    /// `path`（pack 内相对路径）使用的分隔符：第一个匹配的规则，否则为 pack 级配置
+    pub fn get(&self, path: &Path) -> Markers<'a> {
+        let path = path.to_string_lossy();
+        self.rules
+            .iter()
+            .find(|(files, _)| files.is_match(&path))
+            .map_or(self.default, |&(_, markers)| markers)
+    }
+}
+
+impl<'a> From<Markers<'a>> for MarkerSet<'a> {
+    fn from(default: Markers<'a>) -> Self {
+        MarkerSet {
+            default,
+            rules: Vec::new(),
+        }
+    }
 }
 
 /// 解析后的加密参数，由 [`EncryptedConfig::resolve`] 一次性生成
 pub struct EncryptedParams<'a> {
     pub key: Vec<u8>,
-    pub left_boundary: &'a str,
-    pub right_boundary: &'a str,
+    /// keys from `keyring`, tried after `key` when decrypting
+    pub keyring: Vec<Vec<u8>>,
+    pub marker_set: MarkerSet<'a>,
     pub encrypted_alg: &'a str,
+    /// X25519 recipients (raw public keys), empty for symmetric algorithms
+    pub recipients: Vec<Vec<u8>>,
+    /// whole-file encryption patterns
+    pub files: Option<RegexSet>,
+    /// bind new blocks to their file path and label
+    pub bind_blocks: bool,
+    /// refuse to decrypt unbound blocks (`bind_blocks = "strict"`)
+    pub reject_unbound: bool,
+    pub vault: Option<&'a Path>,
+    /// secrets of `vault`, loaded on first use
+    pub secrets: OnceCell<Secrets>,
+}
+
+impl<'a> EncryptedParams<'a> {
+    /// `path`（pack 内相对路径）使用的分隔符，见 [`MarkerSet::get`]
+    pub fn markers(&self, path: &Path) -> Markers<'a> {
+        self.marker_set.get(path)
+    }
+
+    /// 所有可用于解密的密钥：`key` 在前，其后为 `keyring`
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        std::iter::once(self.key.as_slice()).chain(self.keyring.iter().map(Vec::as_slice))
+    }
+
+    /// 是否整文件加密：`.enc` 后缀或匹配 `files`，`rel_path` 为 pack 内相对路径
+    pub fn is_whole_file(&self, rel_path: &Path) -> bool {
+        is_whole_file(rel_path, self.files.as_ref())
+    }
+
+    /// vault 中名为 `name` 的 secret，首次调用时读取并解密 vault
+    pub fn secret(&self, name: &str) -> Result<&str> {
+        let vault_path = self
+            .vault
+            .ok_or_else(|| anyhow!("secret {name}: vault is not configured"))?;
+        if self.secrets.get().is_none() {
+            let _ = self.secrets.set(vault::load(vault_path, self)?);
+        }
+        self.secrets
+            .get()
+            .and_then(|secrets| secrets.get(name))
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("secret {name} not found in {}", vault_path.display()))
+    }
+}
+
+/// init / clear 脚本与钩子：`type` + `content` 指定命令，`args`、`env`、`timeout` 可选
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Command {
+    #[serde(flatten)]
+    pub kind: CommandKind,
+
+    /// extra arguments appended to the command line (`$1`... for `ShellStr`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+
+    /// extra environment variables
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+
+    /// kill the command after this many seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(tag = "type", content = "content")]
-pub enum Command {
+pub enum CommandKind {
     /// executable bin / script
     Bin(PathBuf),
 
@@ -117,6 +437,52 @@ pub enum Command {
 
     /// lua script
     Lua(PathBuf),
+
+    /// program looked up in `PATH`
+    Exec {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+/// 钩子列表中的一项：命令，或 `"!"`（丢弃其后合并自全局配置的命令）
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Hook {
+    Unset(Unset),
+    Command(Command),
+}
+
+impl Hook {
+    pub fn command(&self) -> Option<&Command> {
+        match self {
+            Self::Unset(_) => None,
+            Self::Command(command) => Some(command),
+        }
+    }
+}
+
+impl UnsetMarker for Hook {
+    fn is_unset(&self) -> bool {
+        matches!(self, Self::Unset(_))
+    }
+}
+
+/// 钩子可写作单个命令或命令列表
+fn deserialize_hooks<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<Hook>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Hook),
+        Many(Vec<Hook>),
+    }
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(hook) => vec![hook],
+        OneOrMany::Many(hooks) => hooks,
+    }))
 }
 
 impl Config {
@@ -199,7 +565,35 @@ impl Config {
             .encrypted
             .map(|mut encrypted| {
                 encrypted.key_path = expand_path(encrypted.key_path, &context_map)?;
+                encrypted.keyring = encrypted
+                    .keyring
+                    .map(|keyring| {
+                        keyring
+                            .into_iter()
+                            .map(|p| {
+                                util::shell_expand_full_with_context(p, |key| context_map.get(key))
+                            })
+                            .collect::<Result<Vec<_>>>()
+                    })
+                    .transpose()?;
+                if encrypted.runtime_dir.is_some_and(std::convert::identity) {
+                    let runtime = runtime_pack_decrypt().ok_or_else(|| {
+                        anyhow!(
+                            "{}: runtime_dir is enabled but $XDG_RUNTIME_DIR is not set",
+                            pack.display()
+                        )
+                    })?;
+                    encrypted.decrypted_path = Some(runtime.into());
+                }
                 encrypted.decrypted_path = expand_path(encrypted.decrypted_path, &context_map)?;
+                encrypted.vault = Some(match expand_path(encrypted.vault, &context_map)? {
+                    Some(vault) => pack.join(vault),
+                    None => pack
+                        .ancestors()
+                        .map(|dir| dir.join(VAULT_FILE_NAME))
+                        .find(|vault| vault.is_file())
+                        .unwrap_or_else(|| pack.join(VAULT_FILE_NAME)),
+                });
                 anyhow::Ok(encrypted)
             })
             .transpose()?;
@@ -242,96 +636,248 @@ impl Default for Config {
             fold: Some(true),
             init: None,
             clear: None,
+            pre_install: None,
+            post_install: None,
+            pre_remove: None,
+            post_remove: None,
+            on_reload: None,
             encrypted: Some(EncryptedConfig::default()),
         }
     }
 }
 
 impl Command {
+    /// 执行命令，输出经日志（带 pack 前缀）逐行转发：stdout 为 info，stderr 为 warn；
+    /// 非零退出、被信号终止或超时均返回错误
     pub fn execute<I, K, V>(&self, wd: impl AsRef<Path>, envs: I) -> Result<()>
     where
-        I: IntoIterator<Item = (K, V)> + Clone,
+        I: IntoIterator<Item = (K, V)>,
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
-        let mut command = match self {
-            Self::Bin(path) => std::process::Command::new(path.as_os_str()),
-
-            Self::Make(path) => {
-                let mut c = std::process::Command::new("make");
-                c.arg(path.as_os_str());
-                c
-            }
-
-            Self::Shell(path) => {
+        let mut command = match &self.kind {
+            CommandKind::Bin(path) => std::process::Command::new(path.as_os_str()),
+            CommandKind::Make(path) => interpreter("make", path),
+            CommandKind::Shell(path) => interpreter("sh", path),
+            CommandKind::Python(path) => interpreter("python", path),
+            CommandKind::Lua(path) => interpreter("lua", path),
+            CommandKind::ShellStr(_) => {
                 let mut c = std::process::Command::new("sh");
-                c.arg(path.as_os_str());
+                c.args(["-s", "--"]);
                 c
             }
-
-            Self::Python(path) => {
-                let mut c = std::process::Command::new("python");
-                c.arg(path.as_os_str());
+            CommandKind::Exec { program, args } => {
+                let mut c = std::process::Command::new(program);
+                c.args(args);
                 c
             }
+        };
+        command
+            .args(&self.args)
+            .current_dir(wd)
+            .envs(envs)
+            .envs(&self.env)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let name = self.name();
+        debug!("run {command:?}");
 
-            Self::Lua(path) => {
-                let mut c = std::process::Command::new("lua");
-                c.arg(path.as_os_str());
-                c
-            }
+        // ShellStr 的脚本经 stdin 传入，其他命令继承 stdin，便于交互式的 init 脚本
+        let mut child = command
+            .stdin(if matches!(self.kind, CommandKind::ShellStr(_)) {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .spawn()
+            .with_context(|| format!("failed to run {name}"))?;
+        let lines = forward_output(&mut child);
+        if let CommandKind::ShellStr(content) = &self.kind {
+            // stdin 句柄在 write_all 完成后 drop，EOF 已发送
+            child
+                .stdin
+                .take()
+                .ok_or_else(|| anyhow!("open sh error"))?
+                .write_all(content.as_bytes())?;
+        }
 
-            Self::ShellStr(content) => {
-                let mut c = std::process::Command::new("sh");
-                c.current_dir(&wd);
-                c.envs(envs.clone());
-                c.stdin(Stdio::piped());
-                let mut child = c.spawn()?;
-                child
-                    .stdin
-                    .take()
-                    .ok_or_else(|| anyhow!("open sh error"))?
-                    .write_all(content.as_bytes())?;
-                // stdin 句柄在 write_all 完成后自动 drop，EOF 已发送
+        let deadline = self
+            .timeout
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        let status = loop {
+            if let Ok(line) = lines.recv_timeout(OUTPUT_POLL) {
+                line.log();
+            }
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if deadline.is_some_and(|it| Instant::now() >= it) {
+                child.kill()?;
                 child.wait()?;
-                return Ok(());
+                bail!(
+                    "{name} timed out after {}s",
+                    self.timeout.unwrap_or_default()
+                );
             }
         };
-        command.current_dir(wd).envs(envs).status()?;
-        Ok(())
+        // 子进程退出后转发剩余输出；后台进程可能仍持有管道，不等待其关闭
+        while let Ok(line) = lines.recv_timeout(OUTPUT_POLL) {
+            line.log();
+        }
+
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(code) => bail!("{name} exited with code {code}"),
+            None => bail!("{name} was terminated by {status}"),
+        }
+    }
+
+    /// 错误信息中的命令名
+    fn name(&self) -> String {
+        match &self.kind {
+            CommandKind::Bin(path)
+            | CommandKind::Make(path)
+            | CommandKind::Shell(path)
+            | CommandKind::Python(path)
+            | CommandKind::Lua(path) => path.display().to_string(),
+            CommandKind::ShellStr(_) => "shell script".to_owned(),
+            CommandKind::Exec { program, .. } => program.clone(),
+        }
+    }
+}
+
+/// 等待子进程期间读取输出的间隔
+const OUTPUT_POLL: Duration = Duration::from_millis(50);
+
+fn interpreter(program: &str, script: &Path) -> std::process::Command {
+    let mut c = std::process::Command::new(program);
+    c.arg(script.as_os_str());
+    c
+}
+
+/// 子进程输出的一行
+enum OutputLine {
+    Stdout(String),
+    Stderr(String),
+}
+
+impl OutputLine {
+    fn log(self) {
+        match self {
+            Self::Stdout(line) => info!("{line}"),
+            Self::Stderr(line) => warn!("{line}"),
+        }
     }
 }
 
+/// 在后台线程逐行读取子进程的 stdout / stderr；日志前缀是线程局部的，故经 channel 交回调用线程输出。
+/// 非 UTF-8 的行按有损转换输出，读到 EOF 为止，子进程不会因管道写满而阻塞
+fn forward_output(child: &mut std::process::Child) -> Receiver<OutputLine> {
+    fn spawn_reader(
+        stream: impl Read + Send + 'static,
+        tx: Sender<OutputLine>,
+        wrap: fn(String) -> OutputLine,
+    ) {
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            let mut buf = Vec::new();
+            while reader.read_until(b'\n', &mut buf).is_ok_and(|n| n > 0) {
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                // 接收端已退出时仍继续读取，保持管道畅通
+                let _ = tx.send(wrap(line.to_owned()));
+                buf.clear();
+            }
+        });
+    }
+
+    let (tx, rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        spawn_reader(stdout, tx.clone(), OutputLine::Stdout);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        spawn_reader(stderr, tx, OutputLine::Stderr);
+    }
+    rx
+}
+
 impl EncryptedConfig {
-    /// 一次性解析所有加密参数（含密钥文件读取），消除 `command.rs` 中的重复提取逻辑
-    pub fn resolve(&self, pack_name: &str) -> Result<EncryptedParams<'_>> {
-        let key_path = self
-            .key_path
-            .as_ref()
-            .ok_or_else(|| anyhow!("{pack_name}: key_path is not configured"))?;
-        if !key_path.try_exists()? {
-            bail!("{pack_name}: key_path not exist");
+    /// 解析分隔符与 `rules`，无需读取密钥（用于 `encrypt --check` 与 git filter）
+    pub fn markers<'a>(&'a self, pack_name: &str) -> Result<MarkerSet<'a>> {
+        let boundary = |value: Option<&'a str>, name: &str| {
+            value.ok_or_else(|| anyhow!("{pack_name}: {name} is not configured"))
+        };
+        let default = Markers {
+            left: boundary(self.left_boundary.as_deref(), "left_boundary")?,
+            right: boundary(self.right_boundary.as_deref(), "right_boundary")?,
+            plain_left: boundary(self.plain_left_boundary.as_deref(), "plain_left_boundary")?,
+            plain_right: boundary(self.plain_right_boundary.as_deref(), "plain_right_boundary")?,
+        };
+        let mut rules = Vec::new();
+        for rule in self.rules.iter().flatten().filter_map(Rule::markers) {
+            let files = RegexSet::new(&rule.files)
+                .with_context(|| anyhow!("{pack_name}: rules {:?}", rule.files))?;
+            let markers = Markers {
+                left: rule.left_boundary.as_deref().unwrap_or(default.left),
+                right: rule.right_boundary.as_deref().unwrap_or(default.right),
+                plain_left: rule
+                    .plain_left_boundary
+                    .as_deref()
+                    .unwrap_or(default.plain_left),
+                // 明文块通常与密文块使用相同的右分隔符
+                plain_right: rule
+                    .plain_right_boundary
+                    .as_deref()
+                    .or(rule.right_boundary.as_deref())
+                    .unwrap_or(default.plain_right),
+            };
+            rules.push((files, markers));
         }
-        let key_base64 = std::fs::read_to_string(key_path).with_context(|| {
-            format!(
-                "{pack_name}: failed to read from key_path={}",
-                key_path.display()
-            )
-        })?;
-        let key = base64::decode(&key_base64)?;
+        // 明文与密文标记相同时无法区分两者，encrypt 会重复加密
+        for markers in std::iter::once(&default).chain(rules.iter().map(|(_, it)| it)) {
+            if markers.plain_left == markers.left {
+                bail!(
+                    "{pack_name}: plain_left_boundary must differ from left_boundary ({})",
+                    markers.left
+                );
+            }
+        }
+        Ok(MarkerSet { default, rules })
+    }
 
-        let left_boundary = self
-            .left_boundary
+    /// 编译 `files` 正则
+    pub fn files_regex(&self, pack_name: &str) -> Result<Option<RegexSet>> {
+        self.files
             .as_ref()
-            .ok_or_else(|| anyhow!("{pack_name}: left_boundary is not configured"))?
-            .as_str();
+            .map(RegexSet::new)
+            .transpose()
+            .with_context(|| anyhow!("{pack_name}: {:?}", self.files))
+    }
 
-        let right_boundary = self
-            .right_boundary
-            .as_ref()
-            .ok_or_else(|| anyhow!("{pack_name}: right_boundary is not configured"))?
-            .as_str();
+    /// 一次性解析所有加密参数（含密钥文件读取），消除 `command.rs` 中的重复提取逻辑。
+    /// 本机没有密钥时返回 [`KeyUnavailable`]
+    pub fn resolve(&self, pack_name: &str) -> Result<EncryptedParams<'_>> {
+        let mut params = self.resolve_without_key(pack_name)?;
+
+        params.key = self.resolve_key(pack_name, params.encrypted_alg)?;
+
+        if params.encrypted_alg == crypto::X25519_ALG {
+            params.recipients = self.resolve_recipients(pack_name, &params.key)?;
+        }
 
+        params.keyring = self
+            .keyring
+            .iter()
+            .flatten()
+            .map(|key_path| read_key_file(pack_name, key_path))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(params)
+    }
+
+    /// 不读取密钥的加密参数（`key` 为空），仅用于识别加密内容，如密钥不可用时的 install
+    pub fn resolve_without_key(&self, pack_name: &str) -> Result<EncryptedParams<'_>> {
         let encrypted_alg = self
             .encrypted_alg
             .as_ref()
@@ -339,12 +885,187 @@ impl EncryptedConfig {
             .as_str();
 
         Ok(EncryptedParams {
-            key,
-            left_boundary,
-            right_boundary,
+            key: Vec::new(),
+            keyring: Vec::new(),
+            marker_set: self.markers(pack_name)?,
             encrypted_alg,
+            recipients: Vec::new(),
+            files: self.files_regex(pack_name)?,
+            bind_blocks: self.bind_blocks.unwrap_or_default() != BindBlocks::Off,
+            reject_unbound: self.bind_blocks == Some(BindBlocks::Strict),
+            vault: self.vault.as_deref(),
+            secrets: OnceCell::new(),
         })
     }
+
+    /// 解析接收者公钥，并确保包含本机 identity 的公钥，否则加密后自己无法解密
+    fn resolve_recipients(&self, pack_name: &str, identity: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let own = crypto::x25519_public_key(identity)
+            .with_context(|| format!("{pack_name}: invalid X25519 identity"))?
+            .to_vec();
+        let mut recipients = vec![own];
+        for recipient in self.recipients.iter().flatten() {
+            let recipient = base64::decode(recipient)
+                .with_context(|| format!("{pack_name}: invalid recipient {recipient}"))?;
+            if !recipients.contains(&recipient) {
+                recipients.push(recipient);
+            }
+        }
+        Ok(recipients)
+    }
+
+    /// 读取密钥，优先级：`key_env`（已设置时）> `key_cmd` > `key_path`（存在时）> passphrase 派生
+    fn resolve_key(&self, pack_name: &str, encrypted_alg: &str) -> Result<Vec<u8>> {
+        if let Some(key_base64) = self
+            .key_env
+            .as_ref()
+            .and_then(|env| std::env::var(env).ok())
+            .filter(|key_base64| !key_base64.is_empty())
+        {
+            return base64::decode(&key_base64)
+                .with_context(|| format!("{pack_name}: key_env is not a base64 key"));
+        }
+        if let Some(key_cmd) = &self.key_cmd {
+            return run_key_cmd(pack_name, key_cmd);
+        }
+
+        let key_path = self.key_path.as_ref();
+        let key_path_exists = match key_path {
+            Some(key_path) => key_path.try_exists()?,
+            None => false,
+        };
+
+        match (key_path, &self.passphrase) {
+            (Some(key_path), _) if key_path_exists => read_key_file(pack_name, key_path),
+            (_, Some(passphrase)) => passphrase.derive_key(pack_name, encrypted_alg),
+            (Some(_), None) => Err(KeyUnavailable::error(format!(
+                "{pack_name}: key_path not exist"
+            ))),
+            (None, None) => match &self.key_env {
+                Some(env) => Err(KeyUnavailable::error(format!(
+                    "{pack_name}: {env} is not set"
+                ))),
+                None => bail!("{pack_name}: key_path is not configured"),
+            },
+        }
+    }
+}
+
+/// 是否整文件加密：`.enc` 后缀或匹配 `files`。与 `rules` 相同，`files` 匹配 pack 内相对路径
+pub fn is_whole_file(rel_path: &Path, files: Option<&RegexSet>) -> bool {
+    rel_path
+        .extension()
+        .is_some_and(|ext| ext == crypto::ENCRYPTED_FILE_EXT)
+        || files.is_some_and(|re| re.is_match(&rel_path.to_string_lossy()))
+}
+
+/// `key_cmd` 的输出，按命令缓存，多个 pack 共用同一命令时只执行一次
+static KEY_CMD_CACHE: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+/// 通过 `sh -c` 执行 `key_cmd`，stdin/stderr 继承自当前进程（便于密码管理器交互），stdout 为 base64 密钥
+fn run_key_cmd(pack_name: &str, key_cmd: &str) -> Result<Vec<u8>> {
+    let mut cache = KEY_CMD_CACHE
+        .lock()
+        .map_err(|_| anyhow!("{pack_name}: key cache is poisoned"))?;
+    if let Some(key) = cache.get(key_cmd) {
+        return Ok(key.clone());
+    }
+
+    debug!("{pack_name}: run key_cmd");
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(key_cmd)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("{pack_name}: failed to run key_cmd"))?;
+    if !output.status.success() {
+        bail!("{pack_name}: key_cmd exited with {}", output.status);
+    }
+    let key = base64::decode(std::str::from_utf8(&output.stdout)?.trim())
+        .with_context(|| format!("{pack_name}: key_cmd output is not a base64 key"))?;
+    cache.insert(key_cmd.to_owned(), key.clone());
+    Ok(key)
+}
+
+/// 读取 `key_path` 中的 base64 密钥，文件不存在时返回 `KeyUnavailable`
+pub fn read_key_file(pack_name: &str, key_path: &Path) -> Result<Vec<u8>> {
+    let key_base64 = match std::fs::read_to_string(key_path) {
+        Ok(key_base64) => key_base64,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(KeyUnavailable::error(format!(
+                "{pack_name}: key file {} not exist",
+                key_path.display()
+            )));
+        }
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!(
+                    "{pack_name}: failed to read from key_path={}",
+                    key_path.display()
+                )
+            });
+        }
+    };
+    base64::decode(&key_base64)
+        .with_context(|| format!("{pack_name}: {} is not a base64 key", key_path.display()))
+}
+
+/// 口令派生的密钥，按 (salt, iterations, alg) 缓存，同一次调用中只输入口令、派生一次
+static DERIVED_KEY_CACHE: Mutex<BTreeMap<KdfParams, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+/// (salt, iterations, alg)
+type KdfParams = (Vec<u8>, u32, String);
+
+impl PassphraseConfig {
+    /// 从环境变量或交互式输入获取口令，并用 PBKDF2 派生出与算法匹配长度的密钥
+    fn derive_key(&self, pack_name: &str, encrypted_alg: &str) -> Result<Vec<u8>> {
+        let salt = self
+            .salt
+            .as_ref()
+            .ok_or_else(|| anyhow!("{pack_name}: passphrase.salt is not configured"))?;
+        let salt = base64::decode(salt)?;
+        let iterations = self.iterations.unwrap_or(DEFAULT_KDF_ITERATIONS);
+
+        let mut cache = DERIVED_KEY_CACHE
+            .lock()
+            .map_err(|_| anyhow!("{pack_name}: key cache is poisoned"))?;
+        let cache_key = (salt, iterations, encrypted_alg.to_owned());
+        if let Some(key) = cache.get(&cache_key) {
+            return Ok(key.clone());
+        }
+
+        let passphrase = self.read_passphrase(pack_name)?;
+
+        let key = crypto::derive_key(&passphrase, &cache_key.0, iterations, encrypted_alg)?;
+        cache.insert(cache_key, key.clone());
+        Ok(key)
+    }
+
+    /// 优先读取 `env` 指定的环境变量，未设置时在终端中交互式输入
+    fn read_passphrase(&self, pack_name: &str) -> Result<String> {
+        if let Some(passphrase) = self
+            .env
+            .as_ref()
+            .and_then(|env| std::env::var(env).ok())
+            .filter(|passphrase| !passphrase.is_empty())
+        {
+            return Ok(passphrase);
+        }
+        if !std::io::stdin().is_terminal() {
+            return Err(KeyUnavailable::error(format!(
+                "{pack_name}: passphrase is required but stdin is not a terminal{}",
+                self.env
+                    .as_ref()
+                    .map(|env| format!(", set {env} instead"))
+                    .unwrap_or_default()
+            )));
+        }
+        inquire::Password::new(&format!("{pack_name}: passphrase:"))
+            .without_confirmation()
+            .prompt()
+            .map_err(|e| anyhow!("{e}"))
+    }
 }
 
 impl Default for EncryptedConfig {
@@ -352,10 +1073,24 @@ impl Default for EncryptedConfig {
         EncryptedConfig {
             enable: Some(false),
             decrypted_path: Some(default_pack_decrypt().into()),
+            runtime_dir: Some(false),
             left_boundary: Some(DEFAULT_DECRYPT_LEFT_BOUNDARY.into()),
             right_boundary: Some(DEFAULT_DECRYPT_RIGHT_BOUNDARY.into()),
+            plain_left_boundary: Some(DEFAULT_PLAIN_LEFT_BOUNDARY.into()),
+            plain_right_boundary: Some(DEFAULT_PLAIN_RIGHT_BOUNDARY.into()),
             encrypted_alg: Some(DEFAULT_CRYPT_ALG.into()),
             key_path: None,
+            key_cmd: None,
+            key_env: None,
+            passphrase: None,
+            keyring: None,
+            recipients: None,
+            files: None,
+            bind_blocks: Some(BindBlocks::Off),
+            vault: None,
+            rules: None,
+            on_missing_key: Some(MissingKeyPolicy::Fail),
+            placeholder: Some(DEFAULT_PLACEHOLDER.into()),
         }
     }
 }
@@ -366,11 +1101,22 @@ impl SystemInstance for Config {
             name: None,
             symlink_mode: None,
             target: None,
-            ignore: Some(vec![CONFIG_FILE_NAME.to_string()]),
+            ignore: Some(vec![
+                CONFIG_FILE_NAME.to_string(),
+                VAULT_FILE_NAME.to_string(),
+                format!(r"\.{TEMP_FILE_SUFFIX}$"),
+                format!(r"\.{HASH_FILE_SUFFIX}$"),
+                format!(r"\.{BACKUP_FILE_SUFFIX}$"),
+            ]),
             over: None,
             fold: None,
             init: None,
             clear: None,
+            pre_install: None,
+            post_install: None,
+            pre_remove: None,
+            post_remove: None,
+            on_reload: None,
             encrypted: None,
         }
     }
@@ -380,9 +1126,16 @@ impl SystemInstance for Config {
 mod test {
     use merge::Merge;
 
-    use super::{Config, EncryptedConfig};
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    use super::{
+        BindBlocks, Command, CommandKind, Config, EncryptedConfig, Hook, KeyUnavailable,
+        PassphraseConfig, Rule,
+    };
     use crate::merge::Finalize;
     use crate::symlink::SymlinkMode;
+    use crate::util::TestDir;
 
     #[test]
     fn config_merge() {
@@ -395,6 +1148,11 @@ mod test {
             fold: Some(true),
             init: None,
             clear: None,
+            pre_install: None,
+            post_install: None,
+            pre_remove: None,
+            post_remove: None,
+            on_reload: None,
             encrypted: None,
         };
         let right = Config {
@@ -406,6 +1164,11 @@ mod test {
             fold: Some(true),
             init: None,
             clear: None,
+            pre_install: None,
+            post_install: None,
+            pre_remove: None,
+            post_remove: None,
+            on_reload: None,
             encrypted: Some(EncryptedConfig::default()),
         };
 
@@ -420,12 +1183,31 @@ mod test {
                 fold: Some(true),
                 init: None,
                 clear: None,
+                pre_install: None,
+                post_install: None,
+                pre_remove: None,
+                post_remove: None,
+                on_reload: None,
                 encrypted: Some(EncryptedConfig::default()),
             },
             left
         );
     }
 
+    #[test]
+    fn encrypted_merged_by_field() {
+        let mut pack: Config =
+            toml::from_str("[encrypted]\nenable = true\nkey_path = '!'").unwrap();
+        let global: Config =
+            toml::from_str("[encrypted]\nkey_path = 'a.key'\nkey_cmd = 'cat b.key'").unwrap();
+        pack.merge(global);
+        pack.finalize();
+        let encrypted = pack.encrypted.unwrap();
+        assert_eq!(encrypted.enable, Some(true));
+        assert_eq!(encrypted.key_path, None);
+        assert_eq!(encrypted.key_cmd.as_deref(), Some("cat b.key"));
+    }
+
     fn make_config(target: Option<&str>, ignore: Option<Vec<&str>>) -> Config {
         Config {
             name: None,
@@ -436,6 +1218,11 @@ mod test {
             fold: None,
             init: None,
             clear: None,
+            pre_install: None,
+            post_install: None,
+            pre_remove: None,
+            post_remove: None,
+            on_reload: None,
             encrypted: None,
         }
     }
@@ -487,6 +1274,184 @@ mod test {
         assert_eq!(config.ignore, None);
     }
 
+    #[test]
+    fn hooks_merge_and_unset() {
+        // pack: [a.sh, '!'] + global: [g.sh] → 合并后 [a.sh, '!', g.sh] → 截断 → [a.sh]
+        let mut pack: Option<Config> = Some(
+            toml::from_str(
+                r#"
+                post_install = [{ type = "Shell", content = "a.sh" }, "!"]
+                pre_remove = { type = "ShellStr", content = "true" }
+                "#,
+            )
+            .unwrap(),
+        );
+        let global: Option<Config> = Some(
+            toml::from_str(
+                r#"
+                [[post_install]]
+                type = "Shell"
+                content = "g.sh"
+                [[pre_remove]]
+                type = "Shell"
+                content = "g.sh"
+                "#,
+            )
+            .unwrap(),
+        );
+        merge::option::recurse(&mut pack, global);
+        let mut config = pack.unwrap();
+        config.finalize();
+        let commands = |hooks: Option<Vec<Hook>>| -> Vec<CommandKind> {
+            hooks
+                .into_iter()
+                .flatten()
+                .filter_map(|it| it.command().map(|it| it.kind.clone()))
+                .collect()
+        };
+        assert_eq!(
+            commands(config.post_install),
+            vec![CommandKind::Shell("a.sh".into())]
+        );
+        assert_eq!(
+            commands(config.pre_remove),
+            vec![
+                CommandKind::ShellStr("true".to_owned()),
+                CommandKind::Shell("g.sh".into())
+            ]
+        );
+        assert_eq!(config.pre_install, None);
+        assert!(toml::from_str::<Config>(r#"on_reload = "x""#).is_err());
+    }
+
+    #[test]
+    fn key_unavailable_only_when_absent() {
+        let dir = TestDir::new("key");
+        let corrupt = dir.join("corrupt.key");
+        std::fs::write(&corrupt, "not base64!").unwrap();
+        let unavailable =
+            |config: EncryptedConfig| config.resolve("p").err().map(|e| e.is::<KeyUnavailable>());
+
+        let config = |key_path: Option<PathBuf>| EncryptedConfig {
+            key_path,
+            ..EncryptedConfig::default()
+        };
+        assert_eq!(
+            unavailable(config(Some(dir.join("missing.key")))),
+            Some(true)
+        );
+        assert_eq!(unavailable(config(Some(corrupt.clone()))), Some(false));
+        assert_eq!(
+            unavailable(EncryptedConfig {
+                key_env: Some("STOW_CM_TEST_UNSET_KEY".to_owned()),
+                ..EncryptedConfig::default()
+            }),
+            Some(true)
+        );
+        assert_eq!(
+            unavailable(EncryptedConfig {
+                passphrase: Some(PassphraseConfig {
+                    salt: None,
+                    iterations: None,
+                    env: None,
+                }),
+                ..EncryptedConfig::default()
+            }),
+            Some(false)
+        );
+        assert_eq!(
+            unavailable(EncryptedConfig {
+                key_cmd: Some("echo not-a-key".to_owned()),
+                ..EncryptedConfig::default()
+            }),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn bind_blocks_values() {
+        let parse = |value: &str| {
+            toml::from_str::<EncryptedConfig>(&format!("bind_blocks = {value}"))
+                .map(|it| it.bind_blocks)
+        };
+        assert_eq!(parse("false").unwrap(), Some(BindBlocks::Off));
+        assert_eq!(parse("true").unwrap(), Some(BindBlocks::On));
+        assert_eq!(parse(r#""strict""#).unwrap(), Some(BindBlocks::Strict));
+        assert!(parse(r#""yes""#).is_err());
+    }
+
+    #[test]
+    fn encrypted_lists_unset() {
+        let mut config: EncryptedConfig = toml::from_str(
+            r#"
+            keyring = ["work.key", "!", "global.key"]
+            rules = [{ files = ['\.sh$'], left_boundary = "@ENC[" }, "!", { files = [".*"] }]
+            "#,
+        )
+        .unwrap();
+        config.finalize();
+        assert_eq!(config.keyring, Some(vec![PathBuf::from("work.key")]));
+        let rules: Vec<_> = config
+            .rules
+            .iter()
+            .flatten()
+            .filter_map(Rule::markers)
+            .map(|it| it.files.clone())
+            .collect();
+        assert_eq!(rules, vec![vec![r"\.sh$".to_owned()]]);
+
+        let mut config: EncryptedConfig = toml::from_str(r#"keyring = ["!"]"#).unwrap();
+        config.finalize();
+        assert_eq!(config.keyring, None);
+    }
+
+    #[test]
+    fn command_options() {
+        let config: Config = toml::from_str(
+            r#"
+            init = { type = "Exec", content = { program = "fc-cache", args = ["-f"] }, timeout = 30 }
+            [clear]
+            type = "ShellStr"
+            content = "echo $1"
+            args = ["a"]
+            env = { FOO = "bar" }
+            "#,
+        )
+        .unwrap();
+        let init = config.init.unwrap();
+        assert_eq!(
+            init.kind,
+            CommandKind::Exec {
+                program: "fc-cache".to_owned(),
+                args: vec!["-f".to_owned()]
+            }
+        );
+        assert_eq!(init.timeout, Some(30));
+        let clear = config.clear.unwrap();
+        assert_eq!(clear.args, vec!["a".to_owned()]);
+        assert_eq!(clear.env.get("FOO").map(String::as_str), Some("bar"));
+    }
+
+    #[test]
+    fn command_exit_status() {
+        let run = |script: &str| {
+            Command {
+                kind: CommandKind::ShellStr(script.to_owned()),
+                args: vec!["x".to_owned()],
+                env: BTreeMap::new(),
+                timeout: Some(5),
+            }
+            .execute(".", [("K", "v")])
+        };
+        assert!(run("test \"$1$K\" = xv").is_ok());
+        let err = run("echo out; echo err >&2; exit 3").unwrap_err();
+        assert_eq!(err.to_string(), "shell script exited with code 3");
+        // 非 UTF-8 的行之后继续读取，大量输出不会写满管道而超时
+        let err = run("printf '\\377\\n'; head -c 200000 /dev/zero | tr '\\0' a; echo; exit 4")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "shell script exited with code 4");
+    }
+
     #[test]
     fn finalize_array_no_marker_merges_normally() {
         let mut pack = Some(make_config(None, Some(vec!["a"])));
@@ -2,10 +2,38 @@ pub const PACK_ID_ENV: &str = "PACK_ID";
 
 pub const PACK_NAME_ENV: &str = "PACK_NAME";
 
+/// env vars exported to init / clear scripts and hooks
+pub const PACK_PATH_ENV: &str = "PACK_PATH";
+
+pub const PACK_TARGET_ENV: &str = "PACK_TARGET";
+
+pub const PACK_DECRYPTED_PATH_ENV: &str = "PACK_DECRYPTED_PATH";
+
+/// install / remove / reload / clean
+pub const STOW_CM_OP_ENV: &str = "STOW_CM_OP";
+
+/// reserved, always "0": no command that runs scripts and hooks has a dry-run mode yet
+pub const STOW_CM_DRY_RUN_ENV: &str = "STOW_CM_DRY_RUN";
+
+/// file listing the links just created or removed, one per line
+pub const STOW_CM_LINKS_ENV: &str = "STOW_CM_LINKS";
+
 pub const CONFIG_FILE_NAME: &str = "stow-cm.toml";
 
 pub const TRACK_FILE_NAME: &str = "track.toml";
 
+/// encrypted secret vault, looked up in the pack dir and its parent dirs
+pub const VAULT_FILE_NAME: &str = "stow-cm.vault";
+
+/// suffix of the temp file written next to a file before it is atomically replaced
+pub const TEMP_FILE_SUFFIX: &str = "stow-cm-tmp";
+
+/// suffix of the hard-link backup kept while a group of files is replaced
+pub const BACKUP_FILE_SUFFIX: &str = "stow-cm-bak";
+
+/// suffix of the source hash file kept next to each decrypted file
+pub const HASH_FILE_SUFFIX: &str = "stow-cm-hash";
+
 /// if the value of Some(value) is !, it is equivalent to None.
 pub const UNSET_VALUE: &str = "!";
 
@@ -13,4 +41,14 @@ pub const DEFAULT_DECRYPT_LEFT_BOUNDARY: &str = "&{";
 
 pub const DEFAULT_DECRYPT_RIGHT_BOUNDARY: &str = "}";
 
+pub const DEFAULT_PLAIN_LEFT_BOUNDARY: &str = "&+{";
+
+pub const DEFAULT_PLAIN_RIGHT_BOUNDARY: &str = "}";
+
 pub const DEFAULT_CRYPT_ALG: &str = "ChaCha20-Poly1305";
+
+/// stub installed in place of encrypted blocks with `on_missing_key = "placeholder"`
+pub const DEFAULT_PLACEHOLDER: &str = "<secret pending>";
+
+/// PBKDF2-HMAC-SHA256 iterations for passphrase derived keys
+pub const DEFAULT_KDF_ITERATIONS: u32 = 600_000;
@@ -1,4 +1,7 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail};
 use log::debug;
@@ -6,93 +9,672 @@ use ring::aead::{
     AES_128_GCM, AES_256_GCM, Aad, Algorithm, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce,
     UnboundKey,
 };
+use ring::hkdf;
+use ring::pbkdf2;
 use ring::rand::SecureRandom;
 use ring::rand::SystemRandom;
+use sha3::{Digest, Sha3_256};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
 
 use crate::base64;
+use crate::config::EncryptedParams;
 use crate::error::Result;
 use crate::util;
 
-/// decrypt content
-pub fn encrypt_inline(
+/// asymmetric mode: blocks are sealed to X25519 recipients, `key_path` holds the identity
+pub const X25519_ALG: &str = "X25519";
+
+const X25519_PREFIX: &str = "x25519";
+
+/// prefix of X25519 blocks bound to their file path and label
+const X25519_BOUND_PREFIX: &str = "x25519v2";
+
+/// version tag of self-describing symmetric blocks
+const BLOCK_VERSION: &str = "v1";
+
+/// version tag of symmetric blocks bound to their file path and label
+const BOUND_BLOCK_VERSION: &str = "v2";
+
+const X25519_KEY_LEN: usize = 32;
+
+const X25519_WRAP_INFO: &[u8] = b"stow-cm/x25519/wrap";
+
+/// first line of a whole-file encrypted blob
+const ENCRYPTED_FILE_HEADER: &str = "-----BEGIN STOW-CM ENCRYPTED FILE-----";
+
+/// files with this extension are always encrypted as one blob
+pub const ENCRYPTED_FILE_EXT: &str = "enc";
+
+/// a ciphertext block `secret:<name>` refers to an entry of the pack vault
+pub const SECRET_REF_PREFIX: &str = "secret:";
+
+/// seal the plaintext blocks of content into ciphertext blocks, existing ciphertext blocks are
+/// kept as is; `path` is the pack-relative path of the file
+pub fn encrypt_inline(content: &str, params: &EncryptedParams<'_>, path: &Path) -> Result<String> {
+    seal_inline(content, params, path, |_, _| None)
+}
+
+/// same as [`encrypt_inline`], but a plaintext block whose content and site (path and label) are
+/// unchanged from the ciphertext blocks in `previous` reuses that ciphertext, so encrypting the
+/// same plaintext again gives the same output (e.g. the git clean filter)
+pub fn encrypt_inline_reusing(
     content: &str,
-    alg_name: &str,
-    key: &[u8],
-    left_boundary: &str,
-    right_boundary: &str,
-    unwrap: bool,
+    params: &EncryptedParams<'_>,
+    path: &Path,
+    previous: &str,
 ) -> Result<String> {
-    util::var_inplace(content, left_boundary, right_boundary, unwrap, |content| {
-        encrypt(content, alg_name, key)
+    let markers = params.markers(path);
+    let known: HashMap<(Vec<u8>, String), &str> =
+        util::var_spans(previous, markers.left, markers.right)
+            .into_iter()
+            .filter(|span| is_current_block(span.inner, params))
+            .filter_map(|span| {
+                let aad = site_aad(path, span.label, params);
+                let opened = decrypt_block(span.inner, params, &aad).ok()?;
+                Some(((aad, opened), span.inner))
+            })
+            .collect();
+    seal_inline(content, params, path, |aad, content| {
+        known
+            .get(&(aad.to_vec(), content.to_owned()))
+            .map(|&sealed| sealed.to_owned())
     })
+}
+
+/// whether a block is what [`encrypt_block`] would produce now: same binding, and for symmetric
+/// algorithms the primary key
+fn is_current_block(content: &str, params: &EncryptedParams<'_>) -> bool {
+    is_bound_block(content) == params.bind_blocks
+        && (params.encrypted_alg == X25519_ALG
+            || block_key_id(content).is_some_and(|id| id == key_id(&params.key)))
+}
+
+fn seal_inline(
+    content: &str,
+    params: &EncryptedParams<'_>,
+    path: &Path,
+    reuse: impl Fn(&[u8], &str) -> Option<String>,
+) -> Result<String> {
+    let markers = params.markers(path);
+    util::var_inplace_labeled(
+        content,
+        markers.plain_left,
+        markers.plain_right,
+        true,
+        |content, label| {
+            let aad = site_aad(path, label, params);
+            let sealed = match reuse(&aad, content) {
+                Some(sealed) => sealed,
+                None => encrypt_block(content, params, &aad)?,
+            };
+            Ok(format!("{}{sealed}{}", markers.left, markers.right))
+        },
+    )
     .map(Cow::into_owned)
 }
 
-/// decrypt content
+/// open the ciphertext blocks of content, `path` is the pack-relative path of the file.
+///
+/// - `unwrap`: false turns them back into plaintext blocks (the inverse of [`encrypt_inline`]),
+///   true drops the markers of both kinds and the escapes of literal markers to produce the
+///   installed content
+///
+/// Vault references (`secret:<name>`) are only resolved when unwrapping, otherwise kept as is.
 pub fn decrypt_inline(
     content: &str,
-    alg_name: &str,
-    key: &[u8],
-    left_boundary: &str,
-    right_boundary: &str,
+    params: &EncryptedParams<'_>,
+    path: &Path,
     unwrap: bool,
 ) -> Result<String> {
-    util::var_inplace(content, left_boundary, right_boundary, unwrap, |content| {
-        decrypt(content, alg_name, key)
-    })
+    let markers = params.markers(path);
+    if unwrap {
+        // 解密结果与 secret 原样写入，只有块之外的文本反转义
+        return util::unwrap_inplace(
+            content,
+            &[
+                (markers.left, markers.right),
+                (markers.plain_left, markers.plain_right),
+            ],
+            |kind, content, label| {
+                if kind != 0 {
+                    return Ok(content.to_owned());
+                }
+                if let Some(name) = secret_ref(content) {
+                    return Ok(params.secret(name)?.to_owned());
+                }
+                decrypt_block(content, params, &site_aad(path, label, params))
+            },
+        );
+    }
+    util::var_inplace_labeled(
+        content,
+        markers.left,
+        markers.right,
+        true,
+        |content, label| {
+            if secret_ref(content).is_some() {
+                return Ok(format!("{}{content}{}", markers.left, markers.right));
+            }
+            let opened = decrypt_block(content, params, &site_aad(path, label, params))?;
+            Ok(format!(
+                "{}{opened}{}",
+                markers.plain_left, markers.plain_right
+            ))
+        },
+    )
     .map(Cow::into_owned)
 }
 
-/// decrypt content
-/// return format: <`enc_content_base64>`:<`nonce_base64`>
-pub fn encrypt(content: &str, alg_name: &str, key: &[u8]) -> Result<String> {
+/// content installed while the key is unavailable: every encrypted block (and secret ref) is
+/// replaced with `stub`, plaintext blocks are unwrapped as [`decrypt_inline`] does
+pub fn placeholder_inline(
+    content: &str,
+    params: &EncryptedParams<'_>,
+    path: &Path,
+    stub: &str,
+) -> Result<String> {
+    let markers = params.markers(path);
+    util::unwrap_inplace(
+        content,
+        &[
+            (markers.left, markers.right),
+            (markers.plain_left, markers.plain_right),
+        ],
+        |kind, content, _| {
+            Ok(if kind == 0 {
+                stub.to_owned()
+            } else {
+                content.to_owned()
+            })
+        },
+    )
+}
+
+/// associated data of the block at `label` (as passed by [`util::var_inplace_labeled`]).
+///
+/// The label stops at the previous block of either kind, so a block keeps its associated data
+/// while the other blocks on its line are encrypted or decrypted.
+pub fn site_aad(path: &Path, label: &str, params: &EncryptedParams<'_>) -> Vec<u8> {
+    let markers = params.markers(path);
+    let start = [
+        (markers.left, markers.right),
+        (markers.plain_left, markers.plain_right),
+    ]
+    .into_iter()
+    .filter_map(|(left, right)| {
+        util::var_spans(label, left, right)
+            .last()
+            .map(|it| it.range.end)
+    })
+    .max()
+    .unwrap_or(0);
+    block_aad(path, label.get(start..).unwrap_or_default().trim())
+}
+
+/// associated data binding a block to its pack-relative file path and label
+pub fn block_aad(path: &Path, label: &str) -> Vec<u8> {
+    format!("stow-cm\0{}\0{label}", path.to_string_lossy()).into_bytes()
+}
+
+/// encrypt a single block, with the pack's symmetric key or to its X25519 recipients;
+/// `aad` is only bound when `bind_blocks` is enabled
+pub fn encrypt_block(content: &str, params: &EncryptedParams<'_>, aad: &[u8]) -> Result<String> {
+    encrypt_block_bytes(content.as_bytes(), params, aad)
+}
+
+/// decrypt a single block, the key is picked by the block itself
+pub fn decrypt_block(content: &str, params: &EncryptedParams<'_>, aad: &[u8]) -> Result<String> {
+    Ok(String::from_utf8(decrypt_block_bytes(
+        content, params, aad,
+    )?)?)
+}
+
+/// encrypt a whole file (arbitrary bytes) as one blob: a header line followed by a single block
+pub fn encrypt_file(content: &[u8], params: &EncryptedParams<'_>, path: &Path) -> Result<String> {
+    let block = encrypt_block_bytes(content, params, &block_aad(path, ""))?;
+    Ok(format!("{ENCRYPTED_FILE_HEADER}\n{block}\n"))
+}
+
+/// decrypt a blob produced by [`encrypt_file`] back to the original bytes
+pub fn decrypt_file(content: &str, params: &EncryptedParams<'_>, path: &Path) -> Result<Vec<u8>> {
+    decrypt_block_bytes(encrypted_file_block(content)?, params, &block_aad(path, ""))
+}
+
+/// same as [`encrypt_block`], for arbitrary bytes
+pub fn encrypt_block_bytes(
+    content: &[u8],
+    params: &EncryptedParams<'_>,
+    aad: &[u8],
+) -> Result<String> {
+    let aad = params.bind_blocks.then_some(aad);
+    if params.encrypted_alg == X25519_ALG {
+        seal_to_recipients(content, &params.recipients, aad)
+    } else {
+        encrypt_bytes(content, params.encrypted_alg, &params.key, aad)
+    }
+}
+
+/// 按块格式选择密钥：v1/v2 块按 key id 在 `key_path` 与 `keyring` 中查找，
+/// X25519 块依次尝试各个 identity，旧格式块使用 `encrypted_alg` 与 `key_path`
+pub fn decrypt_block_bytes(
+    content: &str,
+    params: &EncryptedParams<'_>,
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let content = content.trim();
+    if params.reject_unbound && !is_bound_block(content) {
+        bail!(
+            "block is not bound to its file path (bind_blocks = \"strict\"), run `stow-cm reseal`"
+        );
+    }
+    if content.starts_with(X25519_PREFIX) {
+        let mut last_err = None;
+        for identity in params.keys() {
+            match open_with_identity(content, identity, aad) {
+                Ok(origin_data) => return Ok(origin_data),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        return Err(last_err.unwrap_or_else(|| anyhow!("no X25519 identity configured")));
+    }
+    match block_key_id(content) {
+        Some(id) => {
+            let key = params
+                .keys()
+                .find(|key| key_id(key) == id)
+                .ok_or_else(|| anyhow!("no key with id {id} in key_path or keyring"))?;
+            decrypt_bytes(content, params.encrypted_alg, key, aad)
+        }
+        None => decrypt_bytes(content, params.encrypted_alg, &params.key, aad),
+    }
+}
+
+/// the secret name of a vault reference block
+pub fn secret_ref(content: &str) -> Option<&str> {
+    content
+        .trim()
+        .strip_prefix(SECRET_REF_PREFIX)
+        .filter(|name| is_secret_name(name))
+}
+
+/// secret names are non-empty and made of ASCII alphanumerics, `_`, `-` and `.`
+pub fn is_secret_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// whether a block is bound to its file path and label
+pub fn is_bound_block(content: &str) -> bool {
+    let content = content.trim();
+    content.starts_with(&format!("{BOUND_BLOCK_VERSION}:"))
+        || content.starts_with(&format!("{X25519_BOUND_PREFIX}:"))
+}
+
+/// whether the content is a whole-file encrypted blob
+pub fn is_encrypted_file(content: &[u8]) -> bool {
+    content.starts_with(ENCRYPTED_FILE_HEADER.as_bytes())
+}
+
+/// replace the single block of a whole-file encrypted blob, keeping the header
+pub fn map_file_block(
+    content: &str,
+    convert: impl FnOnce(&str) -> Result<String>,
+) -> Result<String> {
+    let block = convert(encrypted_file_block(content)?)?;
+    Ok(format!("{ENCRYPTED_FILE_HEADER}\n{block}\n"))
+}
+
+fn encrypted_file_block(content: &str) -> Result<&str> {
+    content
+        .strip_prefix(ENCRYPTED_FILE_HEADER)
+        .map(str::trim)
+        .ok_or_else(|| anyhow!("missing encrypted file header {ENCRYPTED_FILE_HEADER}"))
+}
+
+/// decrypted file name of a pack file: the `.enc` suffix is removed
+pub fn decrypted_file_path(path: &Path) -> PathBuf {
+    if path
+        .extension()
+        .is_some_and(|ext| ext == ENCRYPTED_FILE_EXT)
+    {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// encrypt content
+/// return format: v1:<alg>:<`key_id`>:<`enc_content_base64`>:<`nonce_base64`>,
+/// v2 instead of v1 when the block is bound to `aad`
+pub fn encrypt_bytes(
+    content: &[u8],
+    alg_name: &str,
+    key: &[u8],
+    aad: Option<&[u8]>,
+) -> Result<String> {
     let mut nonce_value = [0_u8; NONCE_LEN];
     SystemRandom::new().fill(&mut nonce_value)?;
     let nonce = Nonce::try_assume_unique_for_key(&nonce_value)?;
 
-    let mut content = content.as_bytes().to_vec();
+    let mut content = content.to_vec();
 
-    let key = {
+    let sealing_key = {
         let unbound_key = UnboundKey::new(algorithm(alg_name)?, key)
             .map_err(|_| anyhow!("key format not valid"))?;
         LessSafeKey::new(unbound_key)
     };
-    key.seal_in_place_append_tag(nonce, Aad::empty(), &mut content)?;
+    sealing_key.seal_in_place_append_tag(
+        nonce,
+        Aad::from(aad.unwrap_or_default()),
+        &mut content,
+    )?;
 
+    let version = if aad.is_some() {
+        BOUND_BLOCK_VERSION
+    } else {
+        BLOCK_VERSION
+    };
     let enc_content_base64 = base64::encode(&content);
     let nonce_base64 = base64::encode(&nonce_value);
-    Ok(format!("{enc_content_base64}:{nonce_base64}"))
-}
-
-/// decrypt content
-pub fn decrypt(content: &str, alg_name: &str, key: &[u8]) -> Result<String> {
-    let splitn: Vec<_> = content.splitn(2, ':').collect();
-
-    let [encrypted_content_base64, nonce_base64] = match splitn[..] {
-        [a, b] => Ok([a, b]),
-        _ => Err(anyhow!(
-            r"encryption markers do not contain nonce information
-        in the format of encrypt_data_base64:nonce_base64
-        content: {content}
-        "
-        )),
-    }?;
-    debug!("encrypted_content={encrypted_content_base64}, nonce={nonce_base64}");
+    Ok(format!(
+        "{version}:{alg_name}:{}:{enc_content_base64}:{nonce_base64}",
+        key_id(key)
+    ))
+}
+
+/// decrypt to bytes, the plaintext does not need to be UTF-8.
+/// v1/v2 blocks carry their own algorithm and key id; `alg_name` is only used for legacy
+/// `encrypt_data_base64:nonce_base64` blocks. `aad` is only checked for bound (v2) blocks
+pub fn decrypt_bytes(content: &str, alg_name: &str, key: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let splitn: Vec<_> = content.trim().splitn(5, ':').collect();
+
+    let (alg_name, encrypted_content_base64, nonce_base64, aad) = match splitn[..] {
+        [
+            version @ (BLOCK_VERSION | BOUND_BLOCK_VERSION),
+            alg,
+            id,
+            data,
+            nonce,
+        ] => {
+            if id != key_id(key) {
+                bail!(
+                    "block is encrypted with key {id}, but key {} is given",
+                    key_id(key)
+                );
+            }
+            let aad = if version == BOUND_BLOCK_VERSION {
+                aad
+            } else {
+                &[]
+            };
+            (alg, data, nonce, aad)
+        }
+        [data, nonce] => (alg_name, data, nonce, &[][..]),
+        _ => bail!(
+            "encryption markers are not in the format of \
+             v1:alg:key_id:encrypt_data_base64:nonce_base64 or encrypt_data_base64:nonce_base64, \
+             content: {content}"
+        ),
+    };
+    debug!("alg={alg_name}, encrypted_content={encrypted_content_base64}, nonce={nonce_base64}");
     let mut encrypted_content = base64::decode(encrypted_content_base64)?;
     let nonce = base64::decode(nonce_base64)?;
 
-    let key = {
+    let opening_key = {
         let unbound_key = UnboundKey::new(algorithm(alg_name)?, key)
             .map_err(|_| anyhow!("key format not valid"))?;
         LessSafeKey::new(unbound_key)
     };
-    let origin_data = key.open_in_place(
-        Nonce::try_assume_unique_for_key(&nonce)?,
-        Aad::empty(),
-        &mut encrypted_content,
+    let origin_data = opening_key
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(&nonce)
+                .map_err(|_| anyhow!("invalid nonce, expected {NONCE_LEN} bytes"))?,
+            Aad::from(aad),
+            &mut encrypted_content,
+        )
+        .map_err(|_| anyhow!("failed to decrypt block, wrong key or the block was moved"))?;
+    Ok(origin_data.to_vec())
+}
+
+/// key id of a symmetric key: the first 8 bytes of its SHA3-256 digest, hex encoded
+pub fn key_id(key: &[u8]) -> String {
+    let digest = Sha3_256::digest(key);
+    hex::encode(digest.iter().take(8).copied().collect::<Vec<_>>())
+}
+
+/// key id recorded in a v1/v2 block, `None` for legacy and X25519 blocks
+pub fn block_key_id(content: &str) -> Option<&str> {
+    let mut parts = content.trim().splitn(4, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(BLOCK_VERSION | BOUND_BLOCK_VERSION), Some(_), Some(id)) => Some(id),
+        _ => None,
+    }
+}
+
+/// key length in bytes of the algorithm
+pub fn key_len(alg_name: &str) -> Result<usize> {
+    if alg_name == X25519_ALG {
+        return Ok(X25519_KEY_LEN);
+    }
+    Ok(algorithm(alg_name)?.key_len())
+}
+
+/// generate a random key of the algorithm's key length
+pub fn generate_key(alg_name: &str) -> Result<Vec<u8>> {
+    let mut key = vec![0_u8; key_len(alg_name)?];
+    SystemRandom::new().fill(&mut key)?;
+    Ok(key)
+}
+
+/// derive a key of the algorithm's key length from passphrase with PBKDF2-HMAC-SHA256
+pub fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    iterations: u32,
+    alg_name: &str,
+) -> Result<Vec<u8>> {
+    let iterations =
+        NonZeroU32::new(iterations).ok_or_else(|| anyhow!("kdf iterations must be positive"))?;
+    let mut key = vec![0_u8; key_len(alg_name)?];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    Ok(key)
+}
+
+/// X25519 public key (recipient) of the identity (private key)
+pub fn x25519_public_key(identity: &[u8]) -> Result<[u8; X25519_KEY_LEN]> {
+    let secret = StaticSecret::from(x25519_bytes(identity)?);
+    Ok(PublicKey::from(&secret).to_bytes())
+}
+
+/// seal content to X25519 recipients
+/// return format: x25519:<`ephemeral_public_base64`>:<`wrapped_key_base64`,...>:<`enc_content_base64`>
+///
+/// 每个块生成随机内容密钥，用 ChaCha20-Poly1305 加密内容；
+/// 内容密钥再经 ephemeral-static ECDH + HKDF 派生的包裹密钥分别为每个接收者加密。
+/// `x25519v2` instead of `x25519` when the block is bound to `aad`
+pub fn seal_to_recipients(
+    content: &[u8],
+    recipients: &[Vec<u8>],
+    aad: Option<&[u8]>,
+) -> Result<String> {
+    let mut file_key = [0_u8; X25519_KEY_LEN];
+    SystemRandom::new().fill(&mut file_key)?;
+
+    let mut sealed = content.to_vec();
+    single_use_key(&file_key)?.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key([0; NONCE_LEN]),
+        Aad::from(aad.unwrap_or_default()),
+        &mut sealed,
     )?;
-    let origin_data = String::from_utf8(origin_data.to_vec())?;
-    Ok(origin_data)
+
+    let prefix = if aad.is_some() {
+        X25519_BOUND_PREFIX
+    } else {
+        X25519_PREFIX
+    };
+    let (ephemeral_public, stanzas) = wrap_file_key(&file_key, recipients)?;
+    Ok(format!(
+        "{prefix}:{ephemeral_public}:{stanzas}:{}",
+        base64::encode(&sealed)
+    ))
+}
+
+/// open a block sealed by [`seal_to_recipients`] with the X25519 identity,
+/// `aad` is only checked for bound blocks
+pub fn open_with_identity(content: &str, identity: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let block = X25519Block::parse(content)?;
+    let file_key = block.unwrap_file_key(identity)?;
+    let mut sealed = base64::decode(block.sealed)?;
+    let aad = if block.prefix == X25519_BOUND_PREFIX {
+        aad
+    } else {
+        &[]
+    };
+    let origin_data = single_use_key(&file_key)?
+        .open_in_place(
+            Nonce::assume_unique_for_key([0; NONCE_LEN]),
+            Aad::from(aad),
+            &mut sealed,
+        )
+        .map_err(|_| anyhow!("failed to decrypt block, the block was moved"))?;
+    Ok(origin_data.to_vec())
+}
+
+/// re-wrap the content key of a sealed block to a new recipient list, the content is not re-encrypted
+pub fn rewrap(content: &str, identity: &[u8], recipients: &[Vec<u8>]) -> Result<String> {
+    let block = X25519Block::parse(content)?;
+    let file_key = block.unwrap_file_key(identity)?;
+    let (ephemeral_public, stanzas) = wrap_file_key(&file_key, recipients)?;
+    Ok(format!(
+        "{}:{ephemeral_public}:{stanzas}:{}",
+        block.prefix, block.sealed
+    ))
+}
+
+/// X25519 模式下解析后的块
+struct X25519Block<'a> {
+    prefix: &'a str,
+    ephemeral_public: &'a str,
+    stanzas: &'a str,
+    sealed: &'a str,
+}
+
+impl<'a> X25519Block<'a> {
+    fn parse(content: &'a str) -> Result<Self> {
+        let splitn: Vec<_> = content.trim().splitn(4, ':').collect();
+        match splitn[..] {
+            [
+                prefix @ (X25519_PREFIX | X25519_BOUND_PREFIX),
+                ephemeral_public,
+                stanzas,
+                sealed,
+            ] => Ok(X25519Block {
+                prefix,
+                ephemeral_public,
+                stanzas,
+                sealed,
+            }),
+            _ => bail!(
+                "encryption markers are not in the format of \
+                 x25519:ephemeral_public_base64:wrapped_keys:encrypt_data_base64, content: {content}"
+            ),
+        }
+    }
+
+    /// 逐个尝试 stanza，用本机 identity 解出内容密钥
+    fn unwrap_file_key(&self, identity: &[u8]) -> Result<[u8; X25519_KEY_LEN]> {
+        let secret = StaticSecret::from(x25519_bytes(identity)?);
+        let recipient = PublicKey::from(&secret);
+        let ephemeral_public =
+            PublicKey::from(x25519_bytes(&base64::decode(self.ephemeral_public)?)?);
+        let wrap_key = wrap_key(
+            &secret.diffie_hellman(&ephemeral_public),
+            &ephemeral_public,
+            &recipient,
+        )?;
+        for stanza in self.stanzas.split(',') {
+            let mut wrapped = base64::decode(stanza)?;
+            if let Ok(file_key) = wrap_key.open_in_place(
+                Nonce::assume_unique_for_key([0; NONCE_LEN]),
+                Aad::empty(),
+                &mut wrapped,
+            ) {
+                return x25519_bytes(file_key);
+            }
+        }
+        bail!("the identity is not a recipient of this block")
+    }
+}
+
+/// 为所有接收者包裹内容密钥，返回 `(ephemeral_public_base64, stanzas)`
+fn wrap_file_key(file_key: &[u8], recipients: &[Vec<u8>]) -> Result<(String, String)> {
+    if recipients.is_empty() {
+        bail!("no X25519 recipients configured");
+    }
+    let mut ephemeral = [0_u8; X25519_KEY_LEN];
+    SystemRandom::new().fill(&mut ephemeral)?;
+    let ephemeral_secret = StaticSecret::from(ephemeral);
+    let ephemeral_public = PublicKey::from(&ephemeral_secret);
+
+    let stanzas = recipients
+        .iter()
+        .map(|recipient| {
+            let recipient = PublicKey::from(x25519_bytes(recipient)?);
+            let wrap_key = wrap_key(
+                &ephemeral_secret.diffie_hellman(&recipient),
+                &ephemeral_public,
+                &recipient,
+            )?;
+            let mut wrapped = file_key.to_vec();
+            wrap_key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key([0; NONCE_LEN]),
+                Aad::empty(),
+                &mut wrapped,
+            )?;
+            Ok(base64::encode(&wrapped))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((
+        base64::encode(ephemeral_public.as_bytes()),
+        stanzas.join(","),
+    ))
+}
+
+/// `HKDF-SHA256(salt = ephemeral_public || recipient, ikm = shared_secret)` 派生包裹密钥
+fn wrap_key(
+    shared: &SharedSecret,
+    ephemeral_public: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<LessSafeKey> {
+    if !shared.was_contributory() {
+        bail!("X25519 key agreement produced a low order shared secret");
+    }
+    let salt = [ephemeral_public.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared.as_bytes());
+    let okm = prk
+        .expand(&[X25519_WRAP_INFO], &CHACHA20_POLY1305)
+        .map_err(|_| anyhow!("failed to derive X25519 wrap key"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// 仅加密一次的随机密钥，nonce 固定为全零
+fn single_use_key(key: &[u8]) -> Result<LessSafeKey> {
+    let unbound_key =
+        UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| anyhow!("key format not valid"))?;
+    Ok(LessSafeKey::new(unbound_key))
+}
+
+fn x25519_bytes(key: &[u8]) -> Result<[u8; X25519_KEY_LEN]> {
+    key.try_into()
+        .map_err(|_| anyhow!("X25519 key must be {X25519_KEY_LEN} bytes"))
 }
 
 /// convert algorithmName to algorithm
@@ -102,7 +684,7 @@ fn algorithm(alg_name: &str) -> Result<&'static Algorithm> {
         "AES-256-GCM" => Ok(&AES_256_GCM),
         "ChaCha20-Poly1305" => Ok(&CHACHA20_POLY1305),
         _ => bail!(
-            "unsupported encryption algorithm '{alg_name}', expected: AES-128-GCM, AES-256-GCM, ChaCha20-Poly1305"
+            "unsupported encryption algorithm '{alg_name}', expected: AES-128-GCM, AES-256-GCM, ChaCha20-Poly1305, X25519"
         ),
     }
 }
@@ -122,9 +704,19 @@ fn algorithm(alg_name: &str) -> Result<&'static Algorithm> {
 #[cfg(test)]
 mod test {
 
+    use std::path::Path;
+
     use crate::base64;
+    use crate::config::{EncryptedParams, MarkerSet, Markers};
     use crate::error::Result;
 
+    const MARKERS: Markers<'static> = Markers {
+        left: "&{",
+        right: "}",
+        plain_left: "&+{",
+        plain_right: "}",
+    };
+
     // #[test]
     // fn encrypt_test() -> Result<()> {
     //     // let plain_text = "Hello world!";
@@ -152,10 +744,333 @@ mod test {
 
         let encrypted_text = "sPO5zRwCrZG0J834t/sd/eeB9F2VthSwrnzLAw==";
 
+        let params = EncryptedParams {
+            key,
+            keyring: vec![],
+            marker_set: MARKERS.into(),
+            encrypted_alg: alg_name,
+            recipients: vec![],
+            files: None,
+            bind_blocks: false,
+            reject_unbound: false,
+            vault: None,
+            secrets: std::cell::OnceCell::new(),
+        };
         let origin_text =
-            super::decrypt(&format!("{encrypted_text}:{nonce_base64}"), alg_name, &key)?;
+            super::decrypt_block(&format!("{encrypted_text}:{nonce_base64}"), &params, &[])?;
 
         assert_eq!(plain_text, origin_text);
         Ok(())
     }
+
+    #[test]
+    fn derive_key_test() -> Result<()> {
+        let salt = base64::decode("c3Rvdy1jbS1zYWx0")?;
+        let key = super::derive_key("correct horse", &salt, 1000, "AES-128-GCM")?;
+        assert_eq!(key.len(), 16);
+        assert_eq!(
+            key,
+            super::derive_key("correct horse", &salt, 1000, "AES-128-GCM")?
+        );
+
+        let key = super::derive_key("correct horse", &salt, 1000, "ChaCha20-Poly1305")?;
+        assert_eq!(key.len(), 32);
+
+        let encrypted = super::encrypt_bytes(b"Hello world!", "ChaCha20-Poly1305", &key, None)?;
+        let other = super::derive_key("wrong horse", &salt, 1000, "ChaCha20-Poly1305")?;
+        assert!(super::decrypt_bytes(&encrypted, "ChaCha20-Poly1305", &other, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn x25519_recipients_test() -> Result<()> {
+        let alice = super::generate_key(super::X25519_ALG)?;
+        let bob = super::generate_key(super::X25519_ALG)?;
+        let carol = super::generate_key(super::X25519_ALG)?;
+        let recipients = vec![
+            super::x25519_public_key(&alice)?.to_vec(),
+            super::x25519_public_key(&bob)?.to_vec(),
+        ];
+
+        let sealed = super::seal_to_recipients(b"Hello world!", &recipients, None)?;
+        assert_eq!(
+            super::open_with_identity(&sealed, &alice, &[])?,
+            b"Hello world!"
+        );
+        assert_eq!(
+            super::open_with_identity(&sealed, &bob, &[])?,
+            b"Hello world!"
+        );
+        assert!(super::open_with_identity(&sealed, &carol, &[]).is_err());
+
+        // drop bob, add carol
+        let recipients = vec![
+            super::x25519_public_key(&alice)?.to_vec(),
+            super::x25519_public_key(&carol)?.to_vec(),
+        ];
+        let rewrapped = super::rewrap(&sealed, &alice, &recipients)?;
+        assert_eq!(
+            super::open_with_identity(&rewrapped, &carol, &[])?,
+            b"Hello world!"
+        );
+        assert!(super::open_with_identity(&rewrapped, &bob, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn encrypt_file_test() -> Result<()> {
+        let params = EncryptedParams {
+            key: super::generate_key("AES-256-GCM")?,
+            keyring: vec![],
+            marker_set: MARKERS.into(),
+            encrypted_alg: "AES-256-GCM",
+            recipients: vec![],
+            files: None,
+            bind_blocks: false,
+            reject_unbound: false,
+            vault: None,
+            secrets: std::cell::OnceCell::new(),
+        };
+        // not valid UTF-8
+        let content = [0_u8, 159, 146, 150, 255, b'\n'];
+        let encrypted = super::encrypt_file(&content, &params, Path::new("a.enc"))?;
+        assert!(super::is_encrypted_file(encrypted.as_bytes()));
+        assert_eq!(
+            super::decrypt_file(&encrypted, &params, Path::new("a.enc"))?,
+            content
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn versioned_block_test() -> Result<()> {
+        let personal = super::generate_key("ChaCha20-Poly1305")?;
+        let work = super::generate_key("AES-128-GCM")?;
+        let params = EncryptedParams {
+            key: personal.clone(),
+            keyring: vec![work.clone()],
+            marker_set: MARKERS.into(),
+            encrypted_alg: "ChaCha20-Poly1305",
+            recipients: vec![],
+            files: None,
+            bind_blocks: false,
+            reject_unbound: false,
+            vault: None,
+            secrets: std::cell::OnceCell::new(),
+        };
+
+        // algorithm and key come from the block, not from encrypted_alg / key_path
+        let encrypted = super::encrypt_bytes(b"work secret", "AES-128-GCM", &work, None)?;
+        assert_eq!(
+            super::block_key_id(&encrypted),
+            Some(super::key_id(&work).as_str())
+        );
+        assert_eq!(
+            super::decrypt_block(&encrypted, &params, &[])?,
+            "work secret"
+        );
+
+        let encrypted = super::encrypt_block("personal secret", &params, &[])?;
+        assert_eq!(
+            super::decrypt_block(&encrypted, &params, &[])?,
+            "personal secret"
+        );
+
+        let unknown = super::generate_key("AES-128-GCM")?;
+        let encrypted = super::encrypt_bytes(b"unknown", "AES-128-GCM", &unknown, None)?;
+        assert!(super::decrypt_block(&encrypted, &params, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bound_block_test() -> Result<()> {
+        let mut params = EncryptedParams {
+            key: super::generate_key("ChaCha20-Poly1305")?,
+            keyring: vec![],
+            marker_set: MARKERS.into(),
+            encrypted_alg: "ChaCha20-Poly1305",
+            recipients: vec![],
+            files: None,
+            bind_blocks: true,
+            reject_unbound: false,
+            vault: None,
+            secrets: std::cell::OnceCell::new(),
+        };
+        let path = Path::new("git/config");
+        let encrypted = super::encrypt_inline("token = &+{real}\ntest = &+{fake}", &params, path)?;
+        assert_eq!(
+            super::decrypt_inline(&encrypted, &params, path, true)?,
+            "token = real\ntest = fake"
+        );
+
+        // the same file under another path
+        assert!(super::decrypt_inline(&encrypted, &params, Path::new("other"), true).is_err());
+
+        // swap the two blocks
+        let (token, test) = encrypted.split_once('\n').unwrap();
+        let swapped = format!(
+            "token = {}\ntest = {}",
+            test.trim_start_matches("test = "),
+            token.trim_start_matches("token = ")
+        );
+        assert!(super::decrypt_inline(&swapped, &params, path, true).is_err());
+
+        // bind_blocks = "strict" rejects unbound blocks
+        let unbound = super::encrypt_bytes(b"real", "ChaCha20-Poly1305", &params.key, None)?;
+        assert_eq!(super::decrypt_block(&unbound, &params, &[])?, "real");
+        params.reject_unbound = true;
+        assert!(super::decrypt_block(&unbound, &params, &[]).is_err());
+        assert_eq!(
+            super::decrypt_inline(&encrypted, &params, path, true)?,
+            "token = real\ntest = fake"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn idempotent_inline_test() -> Result<()> {
+        let params = EncryptedParams {
+            key: super::generate_key("ChaCha20-Poly1305")?,
+            keyring: vec![],
+            marker_set: MARKERS.into(),
+            encrypted_alg: "ChaCha20-Poly1305",
+            recipients: vec![],
+            files: None,
+            bind_blocks: true,
+            reject_unbound: false,
+            vault: None,
+            secrets: std::cell::OnceCell::new(),
+        };
+        let path = Path::new("git/config");
+        let plain = "user = &+{me} token = &+{real}\n";
+
+        let encrypted = super::encrypt_inline(plain, &params, path)?;
+        assert!(!encrypted.contains("&+{"));
+        assert_eq!(super::encrypt_inline(&encrypted, &params, path)?, encrypted);
+
+        // a half encrypted line still decrypts, labels skip blocks of the other kind
+        let (first, _) = encrypted.split_once(" token").unwrap();
+        let mixed = format!("{first} token = &+{{real}}\n");
+        assert_eq!(super::decrypt_inline(&mixed, &params, path, false)?, plain);
+        assert_eq!(
+            super::decrypt_inline(
+                &super::encrypt_inline(&mixed, &params, path)?,
+                &params,
+                path,
+                true
+            )?,
+            "user = me token = real\n"
+        );
+
+        assert_eq!(super::decrypt_inline(plain, &params, path, false)?, plain);
+
+        // unchanged blocks keep their ciphertext, changed or moved ones are sealed again
+        assert_eq!(
+            super::encrypt_inline_reusing(plain, &params, path, &encrypted)?,
+            encrypted
+        );
+        let changed = super::encrypt_inline_reusing(
+            "user = &+{me} token = &+{new}\n",
+            &params,
+            path,
+            &encrypted,
+        )?;
+        assert_eq!(changed.split_once(" token").unwrap().0, first);
+        assert_ne!(changed, encrypted);
+        let moved = super::encrypt_inline_reusing(plain, &params, Path::new("other"), &encrypted)?;
+        assert_ne!(moved.split_once(" token").unwrap().0, first);
+        Ok(())
+    }
+
+    #[test]
+    fn secret_ref_test() -> Result<()> {
+        let params = EncryptedParams {
+            key: super::generate_key("ChaCha20-Poly1305")?,
+            keyring: vec![],
+            marker_set: MARKERS.into(),
+            encrypted_alg: "ChaCha20-Poly1305",
+            recipients: vec![],
+            files: None,
+            bind_blocks: false,
+            reject_unbound: false,
+            vault: Some(Path::new("stow-cm.vault")),
+            secrets: std::cell::OnceCell::from(crate::vault::Secrets::from([(
+                "github_token".to_owned(),
+                "ghp_x".to_owned(),
+            )])),
+        };
+        let path = Path::new("git/config");
+        assert_eq!(
+            super::secret_ref(" secret:github_token "),
+            Some("github_token")
+        );
+        assert_eq!(super::secret_ref("secret:a b"), None);
+
+        let content = "token = &{secret:github_token}\n";
+        assert_eq!(super::encrypt_inline(content, &params, path)?, content);
+        assert_eq!(
+            super::decrypt_inline(content, &params, path, false)?,
+            content
+        );
+        assert_eq!(
+            super::decrypt_inline(content, &params, path, true)?,
+            "token = ghp_x\n"
+        );
+        assert!(super::decrypt_inline("&{secret:missing}", &params, path, true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn marker_rules_test() -> Result<()> {
+        let yaml = Markers {
+            left: "#ENC[",
+            right: "]",
+            plain_left: "#PLAIN[",
+            plain_right: "]",
+        };
+        let params = EncryptedParams {
+            key: super::generate_key("ChaCha20-Poly1305")?,
+            keyring: vec![],
+            marker_set: MarkerSet {
+                default: MARKERS,
+                rules: vec![(regex::RegexSet::new([r"\.ya?ml$"])?, yaml)],
+            },
+            encrypted_alg: "ChaCha20-Poly1305",
+            recipients: vec![],
+            files: None,
+            bind_blocks: true,
+            reject_unbound: false,
+            vault: None,
+            secrets: std::cell::OnceCell::new(),
+        };
+
+        let path = Path::new("app/config.yaml");
+        let plain = "token: #PLAIN[abc]\ncmd: echo &+{x} \\#PLAIN[y]\n";
+        let encrypted = super::encrypt_inline(plain, &params, path)?;
+        assert!(encrypted.starts_with("token: #ENC[v2:"));
+        assert!(encrypted.ends_with("cmd: echo &+{x} \\#PLAIN[y]\n"));
+        assert_eq!(
+            super::decrypt_inline(&encrypted, &params, path, false)?,
+            plain
+        );
+        assert_eq!(
+            super::decrypt_inline(&encrypted, &params, path, true)?,
+            "token: abc\ncmd: echo &+{x} #PLAIN[y]\n"
+        );
+
+        // other files keep the pack markers, escaped markers stay literal
+        let path = Path::new("run.sh");
+        let plain = r"echo \&{HOME} &+{pw}";
+        let encrypted = super::encrypt_inline(plain, &params, path)?;
+        assert!(encrypted.starts_with(r"echo \&{HOME} &{v2:"));
+        assert_eq!(
+            super::decrypt_inline(&encrypted, &params, path, true)?,
+            "echo &{HOME} pw"
+        );
+        assert_eq!(
+            super::placeholder_inline(&encrypted, &params, path, "<pending>")?,
+            "echo &{HOME} <pending>"
+        );
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
 use std::fmt::Write as FmtWrite;
 use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::Context;
@@ -9,17 +10,41 @@ use log::debug;
 
 use crate::cli::Cli;
 use crate::cli::Commands;
+use crate::cli::FilterCommands;
+use crate::cli::GitCommands;
+use crate::cli::KeyCommands;
+use crate::cli::SecretCommands;
+use crate::command::CryptoOptions;
 use crate::command::adopt;
+use crate::command::audit;
+use crate::command::cat;
 use crate::command::clean;
 use crate::command::decrypt;
-use crate::command::encrypt;
+use crate::command::diff;
+use crate::command::edit;
+use crate::command::file_packs;
+use crate::command::git_setup;
 use crate::command::init;
 use crate::command::install;
+use crate::command::key_gen;
+use crate::command::key_pub;
+use crate::command::key_rewrap;
+use crate::command::key_rotate;
 use crate::command::list;
+use crate::command::relink_secrets;
 use crate::command::reload;
 use crate::command::remove;
+use crate::command::reseal;
 use crate::command::resolve_pack_ids;
+use crate::command::secret_get;
+use crate::command::secret_list;
+use crate::command::secret_rm;
+use crate::command::secret_set;
 use crate::command::status;
+use crate::command::verify;
+use crate::command::watch;
+use crate::command::{encrypt, encrypt_check};
+use crate::command::{filter_clean, filter_smudge, filter_textconv};
 use crate::config::Config;
 use crate::error::Result;
 
@@ -37,9 +62,11 @@ mod executor;
 mod merge;
 mod merge_tree;
 mod paths;
+mod secret_scan;
 mod symlink;
 mod track_file;
 mod util;
+mod vault;
 
 // Avoid musl's default allocator due to lackluster performance
 // https://nickb.dev/blog/default-musl-allocator-considered-harmful-to-performance
@@ -48,7 +75,7 @@ mod util;
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 macro_rules! dispatch {
-    ($common_config:expr, $paths:expr, $cmd:ident) => {{
+    ($common_config:expr, $paths:expr, $cmd:expr) => {{
         let paths = util::canonicalize($paths)?;
         executor::exec_all(&$common_config, paths, $cmd)?;
     }};
@@ -127,9 +154,60 @@ fn main() -> Result<()> {
             }
             dispatch!(common_config, all_paths, clean);
         }
-        Commands::Encrypt { paths } => dispatch!(common_config, paths, encrypt),
-        Commands::Decrypt { paths } => dispatch!(common_config, paths, decrypt),
-        Commands::Adopt { sources, to } => {
+        Commands::Encrypt {
+            paths,
+            check,
+            dry_run,
+            files,
+        } => {
+            let (paths, options) = crypto_options(paths, dry_run, files)?;
+            if check {
+                dispatch!(common_config, paths, |config, pack| encrypt_check(
+                    config, pack, &options
+                ));
+            } else {
+                dispatch!(common_config, paths, |config, pack| encrypt(
+                    config, pack, &options
+                ));
+            }
+        }
+        Commands::Decrypt {
+            paths,
+            dry_run,
+            files,
+        } => {
+            let (paths, options) = crypto_options(paths, dry_run, files)?;
+            dispatch!(common_config, paths, |config, pack| decrypt(
+                config, pack, &options
+            ));
+        }
+        Commands::Verify { paths } => dispatch!(common_config, paths, verify),
+        Commands::Audit { paths, fix } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            audit(global, paths, fix)?;
+        }
+        Commands::Edit { path } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            edit(global, &path)?;
+        }
+        Commands::Cat { path } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            cat(global, &path)?;
+        }
+        Commands::Adopt {
+            sources,
+            to,
+            encrypt,
+        } => {
             let global = common_config
                 .as_ref()
                 .as_ref()
@@ -140,8 +218,30 @@ fn main() -> Result<()> {
                 Err(e) if e.kind() == std::io::ErrorKind::NotFound => to,
                 Err(e) => return Err(e).with_context(|| format!("path: {}", to.display())),
             };
-            adopt(global, &sources, &to)?;
+            adopt(global, &sources, &to, encrypt)?;
         }
+        Commands::Key { command } => match command {
+            KeyCommands::Gen { path, alg, force } => key_gen(&path, &alg, force)?,
+            KeyCommands::Rotate {
+                paths,
+                new_key,
+                old_key,
+            } => {
+                let global = common_config
+                    .as_ref()
+                    .as_ref()
+                    .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+                key_rotate(global, paths, &new_key, old_key.as_deref())?;
+            }
+            KeyCommands::Pub { path } => key_pub(&path)?,
+            KeyCommands::Rewrap { paths } => {
+                let global = common_config
+                    .as_ref()
+                    .as_ref()
+                    .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+                key_rewrap(global, paths)?;
+            }
+        },
         Commands::List { json } => list(json)?,
         Commands::Status { paths, fix, json } => {
             let global = common_config
@@ -150,6 +250,68 @@ fn main() -> Result<()> {
                 .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
             status(global, paths, fix, json)?;
         }
+        Commands::Diff { paths } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            diff(global, paths)?;
+        }
+        Commands::Watch { paths } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            watch(global, paths)?;
+        }
+        Commands::Reseal { paths } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            reseal(global, paths)?;
+        }
+        Commands::RelinkSecrets { paths } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            relink_secrets(global, paths)?;
+        }
+        Commands::Secret { command } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            match command {
+                SecretCommands::Set { name, pack } => secret_set(global, &pack, &name)?,
+                SecretCommands::Get { name, pack } => secret_get(global, &pack, &name)?,
+                SecretCommands::List { pack } => secret_list(global, &pack)?,
+                SecretCommands::Rm { name, pack } => secret_rm(global, &pack, &name)?,
+            }
+        }
+        Commands::Filter { command } => {
+            let global = common_config
+                .as_ref()
+                .as_ref()
+                .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+            match command {
+                FilterCommands::Clean { path } => filter_clean(global, &path)?,
+                FilterCommands::Smudge { path } => filter_smudge(global, &path)?,
+                FilterCommands::Textconv { pack, path, file } => {
+                    filter_textconv(global, &pack, path.as_deref(), &file)?;
+                }
+            }
+        }
+        Commands::Git { command } => match command {
+            GitCommands::Setup { paths } => {
+                let global = common_config
+                    .as_ref()
+                    .as_ref()
+                    .ok_or_else(|| crate::error::anyhow!("global config not loaded"))?;
+                git_setup(global, &util::canonicalize(paths)?)?;
+            }
+        },
         Commands::Init { path, use_defaults } => {
             let global = common_config.as_ref().as_ref();
             init(&path, global, use_defaults)?;
@@ -158,3 +320,29 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// encrypt / decrypt 的选项；未指定 pack 时由 `--file` 所在的 pack 决定，
+/// 指定 pack 时每个 `--file` 都必须位于其中之一
+fn crypto_options(
+    paths: Vec<PathBuf>,
+    dry_run: bool,
+    files: Vec<PathBuf>,
+) -> Result<(Vec<PathBuf>, CryptoOptions)> {
+    let files = util::canonicalize(files)?;
+    let paths = if paths.is_empty() {
+        file_packs(&files)?
+    } else {
+        let packs = util::canonicalize(paths.clone())?;
+        if let Some(file) = files
+            .iter()
+            .find(|file| !packs.iter().any(|pack| file.starts_with(pack)))
+        {
+            return Err(crate::error::anyhow!(
+                "{} is not in any of the given packs",
+                file.display()
+            ));
+        }
+        paths
+    };
+    Ok((paths, CryptoOptions { dry_run, files }))
+}
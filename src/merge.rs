@@ -49,10 +49,27 @@ impl Finalize for Option<PathBuf> {
     }
 }
 
-impl Finalize for Option<Vec<String>> {
+/// 列表中表示 `"!"` 的元素：截断其后（合并自全局配置）的元素
+pub(crate) trait UnsetMarker {
+    fn is_unset(&self) -> bool;
+}
+
+impl UnsetMarker for String {
+    fn is_unset(&self) -> bool {
+        self.trim() == UNSET_VALUE
+    }
+}
+
+impl UnsetMarker for PathBuf {
+    fn is_unset(&self) -> bool {
+        self.to_str().map(str::trim) == Some(UNSET_VALUE)
+    }
+}
+
+impl<T: UnsetMarker> Finalize for Option<Vec<T>> {
     fn finalize(&mut self) {
         if let Some(vec) = self {
-            if let Some(pos) = vec.iter().position(|s| s.trim() == UNSET_VALUE) {
+            if let Some(pos) = vec.iter().position(UnsetMarker::is_unset) {
                 vec.truncate(pos);
             }
             if vec.is_empty() {
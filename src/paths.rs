@@ -39,6 +39,13 @@ pub fn default_pack_decrypt() -> String {
     format!("{state_home}/decrypted/")
 }
 
+/// pack 解密文件目录模板（`$XDG_RUNTIME_DIR`，通常为 tmpfs），含 `${PACK_ID}` 占位符；
+/// 未设置 `$XDG_RUNTIME_DIR` 时返回 `None`
+pub fn runtime_pack_decrypt() -> Option<String> {
+    dirs::runtime_dir()
+        .map(|runtime| format!("{}/stow-cm/${{PACK_ID}}/decrypted/", runtime.display()))
+}
+
 /// pack 安装目标目录模板，含 `${PACK_NAME}` 占位符
 pub fn default_pack_target() -> String {
     format!("{}/${{PACK_NAME}}/", config_home().display())
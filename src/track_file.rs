@@ -2,6 +2,7 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::crypto::ENCRYPTED_FILE_EXT;
 use crate::symlink::Symlink;
 
 /// track struct
@@ -20,4 +21,32 @@ pub struct Track {
     /// 安装目标目录（安装时记录，供 `list` 等命令使用）
     #[serde(default)]
     pub target: Option<PathBuf>,
+    /// 密钥不可用时未解密安装（被跳过或以 placeholder 安装）的 pack 源文件，reload 后补齐
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<PathBuf>,
+}
+
+impl Track {
+    /// 链接记录对应的 pack 内源路径：指向 `decrypted_path` 的链接映射回 `pack_path`，
+    /// 其余链接直接返回 `src`。整文件加密安装时去掉了 `.enc` 后缀，映射时补回
+    pub fn pack_source(&self, link: &Symlink) -> PathBuf {
+        match (&self.decrypted_path, &self.pack_path) {
+            (Some(decrypted), Some(pack)) => {
+                let Ok(rel) = link.src.strip_prefix(decrypted) else {
+                    return link.src.clone();
+                };
+                let source = pack.join(rel);
+                let mut encrypted = source.clone().into_os_string();
+                encrypted.push(".");
+                encrypted.push(ENCRYPTED_FILE_EXT);
+                let encrypted = PathBuf::from(encrypted);
+                if !source.exists() && encrypted.exists() {
+                    encrypted
+                } else {
+                    source
+                }
+            }
+            _ => link.src.clone(),
+        }
+    }
 }
@@ -1,5 +1,9 @@
 use std::borrow::Cow;
 use std::env::VarError;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
 use std::cell::{Cell, RefCell};
@@ -9,6 +13,7 @@ use sha3::{Digest, Sha3_256};
 use shellexpand::LookupError;
 use walkdir::WalkDir;
 
+use crate::constants::{BACKUP_FILE_SUFFIX, HASH_FILE_SUFFIX, TEMP_FILE_SUFFIX};
 use crate::error::{Result, anyhow};
 use crate::symlink::{Symlink, SymlinkMode};
 
@@ -139,17 +144,216 @@ pub fn change_base_path(
     Ok(new_base.as_ref().join(path.as_ref().strip_prefix(base)?))
 }
 
-/// 扫描字符串中的占位符 `left...right`，对匹配的占位符内容调用 `convert` 进行原地替换。
+/// 递归创建目录，新建的各级目录权限为 0700（存放解密文件）
+pub fn create_private_dir_all(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+        .with_context(|| format!("failed to create dir {}", path.display()))
+}
+
+/// 先用零覆写文件内容并落盘，再删除文件
+pub fn shred_file(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let len = std::fs::symlink_metadata(path)?.len();
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    std::io::copy(&mut std::io::repeat(0).take(len), &mut file)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// 删除目录：其中的普通文件先经 [`shred_file`] 覆写，符号链接等直接删除
+pub fn shred_dir_all(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    for entry in WalkDir::new(path) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            shred_file(entry.path())
+                .with_context(|| format!("failed to shred {}", entry.path().display()))?;
+        }
+    }
+    std::fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+/// 同目录下的临时文件路径 `.<name>.stow-cm-tmp`，保证 rename 不跨文件系统
+pub fn temp_sibling(path: impl AsRef<Path>) -> Result<PathBuf> {
+    hidden_sibling(path.as_ref(), TEMP_FILE_SUFFIX)
+}
+
+/// 解密文件旁记录源文件哈希的文件路径 `.<name>.stow-cm-hash`
+pub fn hash_sibling(path: impl AsRef<Path>) -> Result<PathBuf> {
+    hidden_sibling(path.as_ref(), HASH_FILE_SUFFIX)
+}
+
+/// 批量替换期间原文件的备份路径 `.<name>.stow-cm-bak`
+pub fn backup_sibling(path: impl AsRef<Path>) -> Result<PathBuf> {
+    hidden_sibling(path.as_ref(), BACKUP_FILE_SUFFIX)
+}
+
+fn hidden_sibling(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("path error: {}", path.display()))?;
+    let mut sibling = std::ffi::OsString::from(".");
+    sibling.push(name);
+    sibling.push(".");
+    sibling.push(suffix);
+    Ok(path.with_file_name(sibling))
+}
+
+/// 将内容写入 `path` 的同目录临时文件并沿用原文件权限，返回临时文件路径。
+/// 调用方随后 `rename` 到 `path` 完成原子替换。
 ///
-/// - `unwrap`: true 表示移除分隔符，只保留替换结果；false 保留分隔符包裹替换结果。
+/// 临时文件以原文件权限新建（`create_new`），不会跟随预先放置的符号链接，也不会短暂地比原文件更宽松
+pub fn stage_write(path: impl AsRef<Path>, content: impl AsRef<[u8]>) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let temp = temp_sibling(path)?;
+    // 上次中断残留的临时文件
+    if std::fs::symlink_metadata(&temp).is_ok() {
+        std::fs::remove_file(&temp)?;
+    }
+    let permissions = std::fs::metadata(path).ok().map(|meta| meta.permissions());
+    let mode = permissions.as_ref().map_or(0o666, |it| it.mode() & 0o7777);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp)
+        .and_then(|mut file| {
+            file.write_all(content.as_ref())?;
+            // 创建时的权限受 umask 影响，之后恢复为与原文件完全一致
+            match permissions {
+                Some(permissions) => file.set_permissions(permissions),
+                None => Ok(()),
+            }
+        })
+        .with_context(|| format!("failed to write temp file {}", temp.display()))?;
+    Ok(temp)
+}
+
+/// 字符串中一个有效占位符 `left...right` 的位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VarSpan<'a> {
+    /// 含分隔符的字节范围
+    pub range: Range<usize>,
+    /// 分隔符之间的内容
+    pub inner: &'a str,
+    /// 标签：同一行中占位符之前、上一个占位符之后的文本（去除首尾空白）
+    pub label: &'a str,
+}
+
+impl VarSpan<'_> {
+    /// 占位符起始位置所在的行号（从 1 开始）
+    pub fn line(&self, content: &str) -> usize {
+        content
+            .get(..self.range.start)
+            .map_or(0, |prefix| prefix.matches('\n').count())
+            + 1
+    }
+}
+
+/// 写在 `left` 前使其成为普通文本的转义字符，安装时移除（见 [`unescape_markers`]）。
+/// `left` 前连续的转义字符两两表示一个字面量 `\`，奇数个时 `left` 被转义
+pub const MARKER_ESCAPE: char = '\\';
+
+/// `pos` 处的分隔符是否被 [`MARKER_ESCAPE`] 转义：其前连续的转义字符为奇数个
+pub fn is_escaped(content: &str, pos: usize) -> bool {
+    content.get(..pos).is_some_and(|prefix| {
+        (prefix.len() - prefix.trim_end_matches(MARKER_ESCAPE).len()) % 2 == 1
+    })
+}
+
+/// 从 `from` 开始查找第一个未转义的 `needle`，返回绝对位置
+fn find_unescaped(content: &str, from: usize, needle: &str) -> Option<usize> {
+    let mut search = from;
+    loop {
+        let pos = search + content.get(search..)?.find(needle)?;
+        if !is_escaped(content, pos) {
+            return Some(pos);
+        }
+        search = pos + needle.len();
+    }
+}
+
+/// 反转义 `lefts` 中各分隔符前连续的转义字符：`\&{` 得到字面量 `&{`，`\\&{` 得到 `\&{`。
+/// 不在分隔符前的转义字符保持不变
+#[allow(clippy::string_slice)]
+pub fn unescape_markers<'a>(content: &'a str, lefts: &[&str]) -> Cow<'a, str> {
+    if !content.contains(MARKER_ESCAPE) {
+        return Cow::Borrowed(content);
+    }
+    let mut result = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(pos) = rest.find(MARKER_ESCAPE) {
+        result.push_str(&rest[..pos]);
+        let escapes = &rest[pos..];
+        let after = escapes.trim_start_matches(MARKER_ESCAPE);
+        let count = escapes.len() - after.len();
+        let before_marker = lefts
+            .iter()
+            .any(|left| !left.is_empty() && after.starts_with(left));
+        let keep = if before_marker { count / 2 } else { count };
+        result.extend(std::iter::repeat_n(MARKER_ESCAPE, keep));
+        rest = after;
+    }
+    result.push_str(rest);
+    Cow::Owned(result)
+}
+
+/// 扫描字符串中的所有有效占位符 `left...right`。
+///
+/// - 转义：前面紧跟 [`MARKER_ESCAPE`] 的 `left` 视为普通文本，如 `\&{`。
 /// - 嵌套处理：如果当前 `left` 和对应的 `right` 之间出现了新的 `left`，则当前占位符被视为未闭合，
 ///   其 `left` 作为普通文本保留，让内层的 `left...right` 成为新的匹配。
-/// - 快速路径：如果字符串中不存在 `left` 分隔符，直接返回 `Cow::Borrowed`，零分配。
+/// - 标签：如 `password = &{...}` 的标签为 `password =`。标签不包含其他占位符的内容，
+///   因此不受其他块加解密的影响。
+#[allow(clippy::string_slice)]
+pub fn var_spans<'a>(content: &'a str, left: &str, right: &str) -> Vec<VarSpan<'a>> {
+    let mut spans = Vec::new();
+    let mut search = 0;
+    // 上一个有效占位符结束的位置，标签从此处或行首开始
+    let mut label_start = 0;
+
+    while let Some(abs_left) = find_unescaped(content, search, left) {
+        let after_left = abs_left + left.len();
+        let rest = &content[after_left..];
+
+        // rest 中无 right → 当前 left 及后续所有 left 均无法闭合
+        let Some(ri) = rest.find(right) else {
+            break;
+        };
+        // 嵌套 — 内层 left 先于外层 right 出现，从内层 left 继续扫描
+        if let Some(inner_left) = find_unescaped(content, after_left, left)
+            && inner_left <= after_left + ri
+        {
+            search = inner_left;
+            continue;
+        }
+
+        let end = after_left + ri + right.len();
+        let line_start = content[..abs_left].rfind('\n').map_or(0, |i| i + 1);
+        spans.push(VarSpan {
+            range: abs_left..end,
+            inner: &rest[..ri],
+            label: content[line_start.max(label_start)..abs_left].trim(),
+        });
+        label_start = end;
+        search = end;
+    }
+    spans
+}
+
+/// 对字符串中的占位符 `left...right`（见 [`var_spans`]）调用 `convert(inner, label)` 进行原地替换。
 ///
-/// 采用位置跟踪式循环，在有效占位符分支中利用已扫描到的 `next_left` 直接跳转，
-/// 避免下一轮循环重复扫描已知位置的 `left`。
+/// - `unwrap`: true 表示移除分隔符，只保留替换结果；false 保留分隔符包裹替换结果。
+/// - 快速路径：如果字符串中不存在占位符，直接返回 `Cow::Borrowed`，零分配。
 #[allow(clippy::string_slice)]
-pub fn var_inplace<'a, F>(
+pub fn var_inplace_labeled<'a, F>(
     content: &'a str,
     left: &str,
     right: &str,
@@ -157,62 +361,69 @@ pub fn var_inplace<'a, F>(
     convert: F,
 ) -> Result<Cow<'a, str>>
 where
-    F: Fn(&str) -> Result<String>,
+    F: Fn(&str, &str) -> Result<String>,
 {
     if !content.contains(left) {
         return Ok(Cow::Borrowed(content));
     }
+    let spans = var_spans(content, left, right);
+    if spans.is_empty() {
+        return Ok(Cow::Borrowed(content));
+    }
 
     let mut result = String::with_capacity(content.len());
     let mut pos = 0;
-    let mut next_left: Option<usize> = content[pos..].find(left).map(|o| pos + o);
-
-    while let Some(abs_left) = next_left {
-        result.push_str(&content[pos..abs_left]);
-
-        let after_left = abs_left + left.len();
-        let rest = &content[after_left..];
-        let next_right = rest.find(right);
-        let next_left_rel = rest.find(left);
-        next_left = next_left_rel.map(|li| after_left + li);
-
-        // rest 中无 right → 当前 left 及后续所有 left 均无法闭合
-        let Some(ri) = next_right else {
-            result.push_str(&content[abs_left..]);
-            return Ok(Cow::Owned(result));
-        };
-
-        if next_left_rel.is_none_or(|li| ri < li) {
-            // Case 1: 有效占位符 — right 在 next_left 之前（或无 next_left）
-            let inner = &rest[..ri];
-            let replaced = convert(inner)?;
-            if !unwrap {
-                result.push_str(left);
-            }
-            result.push_str(&replaced);
-            if !unwrap {
-                result.push_str(right);
-            }
+    for span in spans {
+        result.push_str(&content[pos..span.range.start]);
+        let replaced = convert(span.inner, span.label)?;
+        if !unwrap {
+            result.push_str(left);
+        }
+        result.push_str(&replaced);
+        if !unwrap {
+            result.push_str(right);
+        }
+        pos = span.range.end;
+    }
+    result.push_str(&content[pos..]);
+    Ok(Cow::Owned(result))
+}
 
-            if let Some(nl) = next_left {
-                let after_right = after_left + ri + right.len();
-                debug_assert!(after_right <= nl, "left 与 right 重叠，跳转优化不适用");
-                result.push_str(&content[after_right..nl]);
-                pos = nl;
-            } else {
-                result.push_str(&content[after_left + ri + right.len()..]);
-                return Ok(Cow::Owned(result));
+/// 展开安装内容：`markers` 中各种分隔符的占位符（见 [`var_spans`]）替换为
+/// `convert(kind, inner, label)` 的结果并移除分隔符，`kind` 为分隔符在 `markers` 中的下标；
+/// 占位符之外的文本反转义（见 [`unescape_markers`]），替换结果原样保留。
+///
+/// 不同种类的占位符重叠时，靠前种类的优先。
+#[allow(clippy::string_slice)]
+pub fn unwrap_inplace<F>(content: &str, markers: &[(&str, &str)], convert: F) -> Result<String>
+where
+    F: Fn(usize, &str, &str) -> Result<String>,
+{
+    let mut spans: Vec<(usize, &str, VarSpan<'_>)> = Vec::new();
+    for (kind, (left, right)) in markers.iter().enumerate() {
+        for span in var_spans(content, left, right) {
+            let overlapped = spans.iter().any(|(_, _, it)| {
+                it.range.start < span.range.end && span.range.start < it.range.end
+            });
+            if !overlapped {
+                spans.push((kind, left, span));
             }
-        } else if let Some(li) = next_left_rel {
-            // Case 2: 嵌套 — 内层 left 先于外层 right 出现 (ri >= li)
-            let nl = after_left + li;
-            result.push_str(&content[abs_left..nl]);
-            pos = nl;
         }
     }
+    spans.sort_by_key(|(_, _, span)| span.range.start);
 
-    result.push_str(&content[pos..]);
-    Ok(Cow::Owned(result))
+    let lefts: Vec<_> = markers.iter().map(|(left, _)| *left).collect();
+    let mut result = String::with_capacity(content.len());
+    let mut pos = 0;
+    for (kind, left, span) in spans {
+        // 连同占位符的左分隔符一起反转义，其前的 `\\` 才会变为 `\`
+        let text = unescape_markers(&content[pos..span.range.start + left.len()], &lefts);
+        result.push_str(text.strip_suffix(left).unwrap_or(&text));
+        result.push_str(&convert(kind, span.inner, span.label)?);
+        pos = span.range.end;
+    }
+    result.push_str(&unescape_markers(&content[pos..], &lefts));
+    Ok(result)
 }
 
 /// 从路径中提取包名（最后一级目录名）。
@@ -256,6 +467,16 @@ pub fn hash(content: &str) -> String {
     hex::encode(result)
 }
 
+/// 多段内容的哈希，各段带长度前缀以免拼接歧义
+pub fn hash_parts<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha3_256::new();
+    for part in parts {
+        hasher.update(part.len().to_le_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
 thread_local! {
     static LOG_PREFIX: RefCell<Vec<(String, u8)>> = const { RefCell::new(Vec::new()) };
     static COLOR_NEXT: Cell<u8> = const { Cell::new(0) };
@@ -289,13 +510,76 @@ pub(crate) fn get_log_prefixes() -> Vec<(String, u8)> {
     LOG_PREFIX.with(|cell| cell.borrow().clone())
 }
 
+/// 测试用临时目录 `$TMPDIR/stow-cm-<name>-<pid>`，创建时清空，drop 时（包括 panic）删除
+#[cfg(test)]
+pub(crate) struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("stow-cm-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     mod var_inplace {
+        use std::fmt::Write;
+
         use super::*;
 
+        /// 不需要块标签的 [`var_inplace_labeled`]
+        fn var_inplace<'a, F>(
+            content: &'a str,
+            left: &str,
+            right: &str,
+            unwrap: bool,
+            convert: F,
+        ) -> Result<Cow<'a, str>>
+        where
+            F: Fn(&str) -> Result<String>,
+        {
+            var_inplace_labeled(content, left, right, unwrap, |inner, _| convert(inner))
+        }
+
+        #[test]
+        fn labels() {
+            let labels = std::cell::RefCell::new(Vec::new());
+            var_inplace_labeled(
+                "user = &{a} pass: &{b}\n  token=&{c}\n&{d}",
+                "&{",
+                "}",
+                true,
+                |s, label| {
+                    labels.borrow_mut().push(label.to_owned());
+                    Ok(s.to_owned())
+                },
+            )
+            .unwrap();
+            assert_eq!(labels.into_inner(), ["user =", "pass:", "token=", ""]);
+        }
+
         #[test]
         fn nested_inner_wins() {
             assert_eq!(
@@ -353,14 +637,15 @@ mod tests {
 
         #[test]
         fn large_text() {
-            let content = (0..1000)
-                .map(|i| format!("&{{item{i}}}"))
-                .collect::<String>();
+            let content = (0..1000).fold(String::new(), |mut acc, i| {
+                let _ = write!(acc, "&{{item{i}}}");
+                acc
+            });
             let r = var_inplace(&content, "&{", "}", true, |s| Ok(s.to_uppercase())).unwrap();
             for i in 0..1000 {
                 assert!(r.contains(&format!("ITEM{i}")));
             }
-            assert!(!r.contains("&{") && !r.contains("}"));
+            assert!(!r.contains("&{") && !r.contains('}'));
         }
 
         #[test]
@@ -371,6 +656,57 @@ mod tests {
             );
         }
 
+        #[test]
+        fn escaped_left_kept_literal() {
+            assert_eq!(
+                var_inplace(r"a \&{b} &{c} &{d \&{e}", "&{", "}", true, |s| Ok(
+                    s.to_uppercase()
+                ))
+                .unwrap(),
+                r"a \&{b} C D \&{E"
+            );
+            assert_eq!(
+                unescape_markers(r"a \&{b} \&+{c} \x", &["&{", "&+{"]),
+                r"a &{b} &+{c} \x"
+            );
+        }
+
+        #[test]
+        fn escaped_escape() {
+            assert!(!is_escaped(r"\\&{", 2));
+            assert!(is_escaped(r"\\\&{", 3));
+            assert_eq!(
+                var_inplace(r"a \\&{b} \\\&{c}", "&{", "}", true, |s| Ok(
+                    s.to_uppercase()
+                ))
+                .unwrap(),
+                r"a \\B \\\&{c}"
+            );
+            assert_eq!(
+                unescape_markers(r"\\&{a} \\\&{b} \\x", &["&{"]),
+                r"\&{a} \&{b} \\x"
+            );
+        }
+
+        #[test]
+        fn unwrap_outside_blocks_only() {
+            let markers = [("&{", "}"), ("&+{", "}")];
+            let unwrapped = unwrap_inplace(
+                r"a \\&{b} \&{c} &+{p} \\\&+{q} &{s}",
+                &markers,
+                |kind, inner, _| {
+                    Ok(match (kind, inner) {
+                        // 解密结果中的分隔符与转义保持原样
+                        (0, "s") => r"x \&{y} \\z".to_owned(),
+                        (0, _) => inner.to_uppercase(),
+                        _ => inner.to_owned(),
+                    })
+                },
+            )
+            .unwrap();
+            assert_eq!(unwrapped, r"a \B &{c} p \&+{q} x \&{y} \\z");
+        }
+
         #[test]
         fn keep_delimiters_with_unclosed() {
             assert_eq!(
@@ -379,4 +715,24 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn sibling_names() {
+        assert_eq!(
+            temp_sibling("/a/b.conf").unwrap(),
+            Path::new("/a/.b.conf.stow-cm-tmp")
+        );
+        assert_eq!(
+            hash_sibling("/a/b.conf").unwrap(),
+            Path::new("/a/.b.conf.stow-cm-hash")
+        );
+        assert_eq!(
+            backup_sibling("/a/b.conf").unwrap(),
+            Path::new("/a/.b.conf.stow-cm-bak")
+        );
+        assert_ne!(
+            hash_parts([&b"ab"[..], b"c"]),
+            hash_parts([&b"a"[..], b"bc"])
+        );
+    }
 }
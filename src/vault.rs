@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::config::EncryptedParams;
+use crate::constants::VAULT_FILE_NAME;
+use crate::crypto;
+use crate::error::Result;
+use crate::util;
+
+/// secret 名称 → secret
+pub type Secrets = BTreeMap<String, String>;
+
+/// vault 密文绑定的固定路径，与 vault 实际位置无关，多个 pack 可共用同一 vault
+fn aad_path() -> &'static Path {
+    Path::new(VAULT_FILE_NAME)
+}
+
+/// 读取并解密 vault，vault 不存在时视为空
+pub fn load(path: &Path, params: &EncryptedParams<'_>) -> Result<Secrets> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Secrets::new()),
+        Err(e) => return Err(e).with_context(|| format!("path: {}", path.display())),
+    };
+    let plain = crypto::decrypt_file(&content, params, aad_path())
+        .with_context(|| format!("failed to decrypt vault {}", path.display()))?;
+    Ok(toml::from_str(std::str::from_utf8(&plain)?)?)
+}
+
+/// 将 vault 整体加密为单个块，经临时文件原子替换
+pub fn save(path: &Path, secrets: &Secrets, params: &EncryptedParams<'_>) -> Result<()> {
+    let content = crypto::encrypt_file(toml::to_string(secrets)?.as_bytes(), params, aad_path())?;
+    let temp = util::stage_write(path, content)?;
+    std::fs::rename(&temp, path).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// `path` 是否为 `params` 中配置的 vault，按规范化路径比较
+pub fn is_vault(path: &Path, params: &EncryptedParams<'_>) -> bool {
+    let canonical =
+        |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    params
+        .vault
+        .is_some_and(|vault| canonical(vault) == canonical(path))
+}
+
+/// vault 中的块绑定的路径，其他文件返回 `None`
+pub fn rel_path(path: &Path, params: &EncryptedParams<'_>) -> Option<&'static Path> {
+    is_vault(path, params).then(aad_path)
+}